@@ -8,8 +8,8 @@ new_ssid = os.environ.get("SSID")
 new_pwd = os.environ.get("PASS")
 
 if not new_ssid or not new_pwd:
-    print("You must set the 'SSID' and 'PASS' environment variables when generating this firmware file")
-    sys.exit(1)
+    print("'SSID' and 'PASS' are not set; the firmware will start its provisioning access point on first boot")
+    sys.exit(0)
 
 new_ssid = new_ssid.encode()
 new_pwd = new_pwd.encode()
@@ -0,0 +1,294 @@
+// Runs the firmware's playback scheduling on the host.
+//
+// The modules below are the firmware's own sources; time comes from a fake
+// clock and audio goes to a player that models the I2S DMA ring as a FIFO and
+// records when each write reaches the DAC.
+
+#[path = "../../src/drift.rs"]
+pub mod drift;
+#[path = "../../src/eq.rs"]
+pub mod eq;
+#[path = "../../src/limiter.rs"]
+pub mod limiter;
+#[path = "../../src/link.rs"]
+pub mod link;
+#[path = "../../src/mdns.rs"]
+pub mod mdns;
+#[path = "../../src/ramp.rs"]
+pub mod ramp;
+#[path = "../../src/reconnect.rs"]
+pub mod reconnect;
+#[path = "../../src/resample.rs"]
+pub mod resample;
+#[path = "../../src/ringbuf.rs"]
+pub mod ringbuf;
+#[path = "../../src/sched.rs"]
+pub mod sched;
+#[path = "../../src/volume.rs"]
+pub mod volume;
+
+use snapcast_client::playback::Player;
+use snapcast_client::proto::TimeVal;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::Duration;
+
+use sched::{Clock, Decision, Outcome, Scheduler};
+
+pub const RATE: u16 = 48_000;
+pub const CHANNELS: usize = 2;
+
+/// Real time drives the DAC; the clock the scheduler reads may be stepped away
+/// from it, like SNTP does
+#[derive(Clone, Default)]
+pub struct FakeClock {
+    real: Rc<Cell<Duration>>,
+    step_us: Rc<Cell<i64>>,
+}
+
+impl FakeClock {
+    pub fn real(&self) -> Duration {
+        self.real.get()
+    }
+
+    pub fn advance_to(&self, t: Duration) {
+        if t > self.real.get() {
+            self.real.set(t);
+        }
+    }
+
+    /// Steps the clock read by the scheduler, real time is unaffected
+    pub fn step(&self, us: i64) {
+        self.step_us.set(self.step_us.get() + us);
+    }
+
+    /// Converts a real time into what the scheduler's clock reads at it
+    pub fn read_at(&self, real: Duration) -> Duration {
+        let us = real.as_micros() as i64 + self.step_us.get();
+        Duration::from_micros(us.max(0) as u64)
+    }
+}
+
+impl Clock for FakeClock {
+    fn elapsed(&self) -> Duration {
+        self.read_at(self.real())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Write {
+    /// When the first frame reaches the DAC, as read on the scheduler's clock
+    pub at: Duration,
+    pub frames: usize,
+    /// Left channel of the first frame: its index in the chunk, see `Sim::feed`
+    pub first_frame: i16,
+    /// Right channel of the first frame: the chunk's tag, 0 for silence
+    pub tag: i16,
+}
+
+/// Models the DMA ring like the firmware's player: a write returns once the
+/// ring is full again, within `jitter` of `latency`, and an empty ring is
+/// primed so that a write after idling also reaches the DAC after `latency`
+pub struct RecordingPlayer {
+    clock: FakeClock,
+    latency: Duration,
+    /// Peak to peak spread of the queue left behind by a write
+    jitter: Duration,
+    /// DAC crystal error, parts per million; positive plays fast
+    ppm: f64,
+    /// Real time at which the last queued frame has played out
+    queued_until: Option<Duration>,
+    seed: u32,
+    pub writes: Vec<Write>,
+    /// Every sample written, if asked for
+    pub samples: Option<Vec<i16>>,
+}
+
+impl RecordingPlayer {
+    pub fn new(clock: FakeClock, latency: Duration) -> RecordingPlayer {
+        RecordingPlayer {
+            clock,
+            latency,
+            jitter: Duration::ZERO,
+            ppm: 0.0,
+            queued_until: None,
+            seed: 1,
+            writes: vec![],
+            samples: None,
+        }
+    }
+
+    pub fn with_capture(mut self) -> RecordingPlayer {
+        self.samples = Some(vec![]);
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: Duration) -> RecordingPlayer {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_ppm(mut self, ppm: f64) -> RecordingPlayer {
+        self.ppm = ppm;
+        self
+    }
+
+    fn queued(&self) -> Option<Duration> {
+        let now = self.clock.real();
+        self.queued_until.filter(|q| *q > now).map(|q| q - now)
+    }
+
+    /// Uniform in [-jitter / 2, jitter / 2], deterministic
+    fn next_jitter_us(&mut self) -> i64 {
+        self.seed = self.seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        let span = self.jitter.as_micros() as i64;
+        if span == 0 {
+            return 0;
+        }
+        i64::from(self.seed >> 8) % span - span / 2
+    }
+}
+
+impl Player for RecordingPlayer {
+    fn play(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &mut [i16]) -> anyhow::Result<()> {
+        let now = self.clock.real();
+        let start = match self.queued() {
+            Some(q) => now + q,
+            None => now + self.latency,
+        };
+        let frames = buf.len() / CHANNELS;
+        let secs = frames as f64 / f64::from(RATE) / (1.0 + self.ppm / 1e6);
+        let end = start + Duration::from_secs_f64(secs);
+        self.queued_until = Some(end);
+        self.writes.push(Write {
+            at: self.clock.read_at(start),
+            frames,
+            first_frame: buf.first().copied().unwrap_or(0),
+            tag: buf.get(1).copied().unwrap_or(0),
+        });
+        if let Some(samples) = self.samples.as_mut() {
+            samples.extend_from_slice(buf);
+        }
+
+        // blocks until the ring has room for everything
+        let left_us = self.latency.as_micros() as i64 + self.next_jitter_us();
+        let returns_at = end.saturating_sub(Duration::from_micros(left_us.max(0) as u64));
+        self.clock.advance_to(returns_at);
+        Ok(())
+    }
+
+    fn latency_ms(&self) -> anyhow::Result<u16> {
+        let queued = self.queued().unwrap_or(self.latency);
+        Ok(((queued.as_micros() + 500) / 1000) as u16)
+    }
+
+    fn set_volume(&mut self, _val: u8) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn sample_rate(&self) -> u16 {
+        RATE
+    }
+}
+
+/// A chunk on the server's timeline
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk {
+    /// Real time at which it can be taken off the ring buffer
+    pub arrival: Duration,
+    /// Server timestamp of its first frame
+    pub audible_at: Duration,
+    pub frames: usize,
+    /// Written into every frame, must not be 0
+    pub tag: i16,
+}
+
+/// `count` back to back chunks of `chunk_ms`, each arriving `buffer_ms` before
+/// it is audible, starting at `start`
+pub fn stream(start: Duration, count: usize, chunk_ms: u64, buffer_ms: u64) -> Vec<Chunk> {
+    (0..count)
+        .map(|i| {
+            let t = start + Duration::from_millis(i as u64 * chunk_ms);
+            Chunk {
+                arrival: t,
+                audible_at: t + Duration::from_millis(buffer_ms),
+                frames: (chunk_ms * u64::from(RATE) / 1000) as usize,
+                tag: (i % 30_000) as i16 + 1,
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Step {
+    pub decision: Decision,
+    pub outcome: Option<Outcome>,
+}
+
+/// The consumer side of `handle_samples`: pops chunks once they have arrived,
+/// schedules and plays them
+pub struct Sim {
+    pub clock: FakeClock,
+    pub player: RecordingPlayer,
+    pub sched: Scheduler,
+    /// Per-client latency, as set from Snapweb
+    pub client_latency_ms: i32,
+    buf: Vec<i16>,
+}
+
+impl Sim {
+    pub fn new(player: RecordingPlayer) -> Sim {
+        Sim {
+            clock: player.clock.clone(),
+            player,
+            // the samples carry frame indices and tags, which fades would scale
+            sched: Scheduler::new(CHANNELS).with_fade(Duration::ZERO),
+            client_latency_ms: 0,
+            buf: vec![],
+        }
+    }
+
+    pub fn feed(&mut self, chunk: &Chunk) -> Step {
+        self.clock.advance_to(chunk.arrival);
+        let offset = self.client_latency_ms + i32::from(self.player.latency_ms().unwrap());
+        let decision = self
+            .sched
+            .schedule(TimeVal::from(chunk.audible_at), offset, &self.clock);
+        if !decision.plays() && !self.sched.fades_out() {
+            return Step {
+                decision,
+                outcome: None,
+            };
+        }
+
+        let len = chunk.frames * CHANNELS;
+        self.buf.clear();
+        for frame in 0..chunk.frames {
+            self.buf.extend([frame as i16, chunk.tag]);
+        }
+        self.buf
+            .resize(len + drift::MAX_FRAMES_PER_CHUNK * CHANNELS, 0);
+        let outcome = self
+            .sched
+            .play(decision, &mut self.buf, len, &mut self.player)
+            .unwrap();
+        Step {
+            decision,
+            outcome: Some(outcome),
+        }
+    }
+
+    pub fn feed_all(&mut self, chunks: &[Chunk]) -> Vec<Step> {
+        chunks.iter().map(|c| self.feed(c)).collect()
+    }
+
+    /// The writes of audio (not silence) tagged `tag`
+    pub fn writes_of(&self, tag: i16) -> impl Iterator<Item = &Write> {
+        self.player.writes.iter().filter(move |w| w.tag == tag)
+    }
+}
@@ -0,0 +1,76 @@
+use esp_snapcast_sim::eq::{self, Equalizer};
+use esp_snapcast_sim::{CHANNELS, RATE};
+
+use std::f64::consts::PI;
+
+/// Level in dB of a full-scale sine at `freq` after the bands, once settled
+fn response(bands: &str, freq: f64) -> f64 {
+    let mut eq = Equalizer::new(eq::parse_bands(bands).unwrap(), CHANNELS);
+    let frames = usize::from(RATE) / 2;
+    let amplitude = 16_000.0;
+    let mut buf: Vec<i16> = (0..frames)
+        .flat_map(|i| {
+            let s = amplitude * (2.0 * PI * freq * i as f64 / f64::from(RATE)).sin();
+            [s.round() as i16; CHANNELS]
+        })
+        .collect();
+    // in chunks, like the decoder hands them over
+    for chunk in buf.chunks_mut(1152 * CHANNELS) {
+        eq.process(chunk, u32::from(RATE));
+    }
+    let settled = &buf[buf.len() / 2..];
+    let peak = settled.iter().map(|s| s.unsigned_abs()).max().unwrap();
+    20.0 * (f64::from(peak) / amplitude).log10()
+}
+
+fn assert_near(db: f64, expected: f64) {
+    assert!(
+        (db - expected).abs() < 0.3,
+        "{db:.2}dB, expected {expected}dB"
+    );
+}
+
+#[test]
+fn no_bands_is_transparent() {
+    assert_near(response("", 1000.0), 0.0);
+}
+
+#[test]
+fn highpass_cuts_below_the_corner() {
+    assert_near(response("highpass:100", 1000.0), 0.0);
+    assert_near(response("highpass:100", 100.0), -3.0);
+    // 12dB per octave
+    assert_near(response("highpass:100", 25.0), -24.0);
+}
+
+#[test]
+fn peak_dips_at_its_frequency_only() {
+    let bands = "peak:3000:-6:1.4";
+    assert_near(response(bands, 3000.0), -6.0);
+    assert_near(response(bands, 200.0), 0.0);
+}
+
+#[test]
+fn shelves_and_bands_add_up() {
+    let bands = "lowshelf:200:-6,highshelf:8000:3";
+    assert_near(response(bands, 40.0), -6.0);
+    assert_near(response(bands, 1500.0), 0.0);
+    assert_near(response(bands, 20000.0), 3.0);
+}
+
+#[test]
+fn bands_round_trip() {
+    let s = "highpass:80:0.5,peak:3000:-4.5:1.4,highshelf:10000:2:0.7";
+    assert_eq!(eq::format_bands(&eq::parse_bands(s).unwrap()), s);
+    assert!(eq::parse_bands("peak:3000").is_err());
+    assert!(eq::parse_bands("highpass:80:0:0.7").is_err());
+    assert!(eq::parse_bands("lowpass:10").is_err());
+}
+
+#[test]
+fn largest_coefficients_fit() {
+    // a +12dB high shelf at 20Hz has coefficients near 8, the most Q28 holds;
+    // the low shelf takes the boost back off so that nothing clips
+    let bands = "highshelf:20:12:10,lowshelf:20000:-12";
+    assert_near(response(bands, 1000.0), 0.0);
+}
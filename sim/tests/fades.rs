@@ -0,0 +1,120 @@
+use esp_snapcast_sim::ramp::{Ramp, UNITY};
+use esp_snapcast_sim::sched::{Decision, Outcome, Scheduler};
+use esp_snapcast_sim::{stream, Chunk, FakeClock, RecordingPlayer, Sim, CHANNELS};
+
+use std::time::Duration;
+
+/// 5ms at 48kHz
+const FADE_FRAMES: usize = 240;
+
+/// Fading, and loud: the right channel carries the gain envelope
+fn sim() -> Sim {
+    let player =
+        RecordingPlayer::new(FakeClock::default(), Duration::from_millis(100)).with_capture();
+    let mut sim = Sim::new(player);
+    sim.sched = Scheduler::new(CHANNELS);
+    sim
+}
+
+fn loud(chunks: Vec<Chunk>) -> Vec<Chunk> {
+    chunks
+        .into_iter()
+        .map(|c| Chunk {
+            tag: c.tag + 10_000,
+            ..c
+        })
+        .collect()
+}
+
+/// Right channel of the frames of the `n`th write
+fn right_of_write(sim: &Sim, n: usize) -> Vec<i16> {
+    let writes = &sim.player.writes;
+    let start: usize = writes[..n].iter().map(|w| w.frames).sum();
+    let samples = sim.player.samples.as_ref().unwrap();
+    samples[start * CHANNELS..(start + writes[n].frames) * CHANNELS]
+        .chunks(CHANNELS)
+        .map(|f| f[1])
+        .collect()
+}
+
+fn is_rising(s: &[i16]) -> bool {
+    s.windows(2).all(|w| w[0] <= w[1])
+}
+
+fn is_falling(s: &[i16]) -> bool {
+    s.windows(2).all(|w| w[0] >= w[1])
+}
+
+#[test]
+fn ramp_steps_every_frame() {
+    let mut ramp = Ramp::new(UNITY);
+    ramp.set(0, 4);
+    let mut buf = [1000i16; 10];
+    ramp.apply(&mut buf, 2);
+    assert_eq!(buf, [750, 750, 500, 500, 250, 250, 0, 0, 0, 0]);
+    assert_eq!(ramp.gain(), 0);
+}
+
+#[test]
+fn stream_fades_in() {
+    let mut sim = sim();
+    let chunks = loud(stream(Duration::ZERO, 3, 20, 1000));
+    sim.feed_all(&chunks);
+
+    let first = sim.player.writes.iter().position(|w| w.tag != 0).unwrap();
+    let right = right_of_write(&sim, first);
+    assert!(right[0] < 100, "starts at {}", right[0]);
+    assert!(is_rising(&right[..FADE_FRAMES]));
+    assert_eq!(right[FADE_FRAMES - 1], chunks[0].tag);
+    assert!(right[FADE_FRAMES..].iter().all(|s| *s == chunks[0].tag));
+}
+
+#[test]
+fn jump_fades_out_in_place_of_the_skipped_frames() {
+    let mut sim = sim();
+    let chunks = loud(stream(Duration::ZERO, 40, 40, 1000));
+    sim.feed_all(&chunks[..20]);
+    sim.clock.step(20_000);
+    let steps = sim.feed_all(&chunks[20..]);
+
+    assert!(matches!(
+        steps[0].outcome,
+        Some(Outcome::Played { skipped_frames, .. }) if (900..=1000).contains(&skipped_frames)
+    ));
+    let n = sim.player.writes.len() - 20;
+    let w = sim.player.writes[n];
+    let Some(Outcome::Played { skipped_frames, .. }) = steps[0].outcome else {
+        unreachable!()
+    };
+    // the fade out does not move the chunk
+    assert_eq!(w.frames, 1920 - skipped_frames);
+
+    let right = right_of_write(&sim, n);
+    let tag = chunks[20].tag;
+    assert!(right[0] > tag - 100, "fade out starts at {}", right[0]);
+    assert!(is_falling(&right[..FADE_FRAMES]));
+    assert_eq!(right[FADE_FRAMES - 1], 0);
+    assert!(is_rising(&right[FADE_FRAMES..2 * FADE_FRAMES]));
+    assert_eq!(right[2 * FADE_FRAMES - 1], tag);
+}
+
+#[test]
+fn dropped_chunk_fades_out_the_stream() {
+    let mut sim = sim();
+    let chunks = loud(stream(Duration::ZERO, 40, 20, 1000));
+    sim.feed_all(&chunks[..20]);
+    sim.clock.step(1_500_000);
+    let step = sim.feed(&chunks[20]);
+
+    assert_eq!(step.decision, Decision::TooLate);
+    assert_eq!(step.outcome, Some(Outcome::FadedOut));
+    let n = sim.player.writes.len() - 1;
+    assert_eq!(sim.player.writes[n].frames, FADE_FRAMES);
+    let right = right_of_write(&sim, n);
+    assert!(is_falling(&right));
+    assert_eq!(right[FADE_FRAMES - 1], 0);
+
+    // only once: the stream is cut now
+    let step = sim.feed(&chunks[21]);
+    assert_eq!(step.outcome, None);
+}
@@ -0,0 +1,66 @@
+use esp_snapcast_sim::limiter::Limiter;
+use esp_snapcast_sim::{CHANNELS, RATE};
+
+use std::f64::consts::PI;
+
+fn sine(freq: f64, amplitude: f64, frames: usize) -> Vec<i16> {
+    (0..frames)
+        .flat_map(|i| {
+            let s = amplitude * (2.0 * PI * freq * i as f64 / f64::from(RATE)).sin();
+            [s.round() as i16; CHANNELS]
+        })
+        .collect()
+}
+
+fn limiter(threshold_db: f32) -> Limiter {
+    Limiter::new(threshold_db, 100, u32::from(RATE), CHANNELS)
+}
+
+fn peak(buf: &[i16]) -> i32 {
+    buf.iter().map(|s| i32::from(*s).abs()).max().unwrap()
+}
+
+#[test]
+fn quiet_audio_is_only_delayed() {
+    let mut l = limiter(-6.0);
+    let input = sine(440.0, 8000.0, 4800);
+    let mut buf = input.clone();
+    l.process(&mut buf);
+
+    let delay = l.lookahead() * CHANNELS;
+    assert_eq!(l.lookahead(), 96);
+    assert!(buf[..delay].iter().all(|s| *s == 0));
+    assert_eq!(buf[delay..], input[..input.len() - delay]);
+}
+
+#[test]
+fn loud_bass_stays_under_the_threshold() {
+    let mut l = limiter(-6.0);
+    let mut buf = sine(60.0, 32000.0, 48_000);
+    for chunk in buf.chunks_mut(1152 * CHANNELS) {
+        l.process(chunk);
+    }
+    // -6dB
+    assert!(peak(&buf) <= 16_423, "peak {}", peak(&buf));
+    // limited, not clipped: well above the threshold before the gain dropped,
+    // the wave keeps its shape
+    let settled = &buf[buf.len() / 2..];
+    assert!(peak(settled) > 15_000, "peak {}", peak(settled));
+    let clipped = settled
+        .chunks(CHANNELS)
+        .filter(|f| i32::from(f[0]).abs() >= 16_423)
+        .count();
+    assert!(clipped < 10, "{clipped} frames at the threshold");
+}
+
+#[test]
+fn gain_recovers_after_a_peak() {
+    let mut l = limiter(-6.0);
+    let mut buf = sine(1000.0, 32000.0, 480);
+    buf.extend(sine(1000.0, 8000.0, 48_000));
+    l.process(&mut buf);
+
+    // 100ms release: the quiet part plays at its level again
+    let tail = &buf[buf.len() - 4800..];
+    assert!((7900..=8000).contains(&peak(tail)), "peak {}", peak(tail));
+}
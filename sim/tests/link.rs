@@ -0,0 +1,37 @@
+use esp_snapcast_sim::link::{Link, RSSI_SAMPLES};
+
+#[test]
+fn counts_drops_and_reasons() {
+    let mut link = Link::new();
+    // failed associations before the first connection are not drops
+    link.disconnected(201);
+    link.connected();
+    link.disconnected(16);
+    link.disconnected(201);
+    link.connected();
+    link.disconnected(16);
+    assert!(!link.is_up());
+    assert_eq!(
+        link.to_json(),
+        r#"{"up":false,"drops":2,"last_reason":16,"reasons":{"16":2,"201":2},"rssi_history":[]}"#
+    );
+}
+
+#[test]
+fn keeps_the_latest_rssi() {
+    let mut link = Link::new();
+    link.connected();
+    for i in 0..RSSI_SAMPLES + 5 {
+        link.sample_rssi(-(i as i8));
+    }
+    let json = link.to_json();
+    let history = json.split_once(r#""rssi_history":["#).unwrap().1;
+    let samples: Vec<i8> = history
+        .trim_end_matches("]}")
+        .split(',')
+        .map(|s| s.parse().unwrap())
+        .collect();
+    assert_eq!(samples.len(), RSSI_SAMPLES);
+    assert_eq!(samples[0], -5);
+    assert_eq!(*samples.last().unwrap(), -(RSSI_SAMPLES as i8 + 4));
+}
@@ -0,0 +1,195 @@
+use esp_snapcast_sim::mdns::{self, Records, Server};
+
+use std::net::SocketAddr;
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.') {
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+}
+
+fn record(out: &mut Vec<u8>, owner: &str, rtype: u16, rdata: &[u8]) {
+    name(out, owner);
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&[0x80, 0x01, 0, 0, 0x11, 0x94]);
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+}
+
+/// A response like avahi's: the PTR answer, then SRV, TXT and A as additional
+/// records
+fn response(instance: &str, host: &str, ip: [u8; 4], port: u16, txt: &[&str]) -> Vec<u8> {
+    let full = format!("{instance}.{}", mdns::SERVICE);
+    let mut p = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 3];
+    let mut ptr = Vec::new();
+    name(&mut ptr, &full);
+    record(&mut p, mdns::SERVICE, 12, &ptr);
+
+    let mut srv = vec![0, 0, 0, 0];
+    srv.extend_from_slice(&port.to_be_bytes());
+    name(&mut srv, host);
+    record(&mut p, &full, 33, &srv);
+
+    let mut rdata = Vec::new();
+    for entry in txt {
+        rdata.push(entry.len() as u8);
+        rdata.extend_from_slice(entry.as_bytes());
+    }
+    record(&mut p, &full, 16, &rdata);
+    record(&mut p, host, 1, &ip);
+    p
+}
+
+fn server(instance: &str, host: &str, addr: &str) -> Server {
+    Server {
+        instance: instance.into(),
+        host: host.into(),
+        addr: addr.parse().unwrap(),
+        txt: Vec::new(),
+    }
+}
+
+#[test]
+fn collects_every_server() {
+    let mut records = Records::default();
+    records
+        .add(&response(
+            "Snapcast",
+            "prod.local",
+            [10, 0, 0, 2],
+            1704,
+            &["version=0.28.0"],
+        ))
+        .unwrap();
+    records
+        .add(&response("Test", "test.local", [10, 0, 0, 3], 1804, &[]))
+        .unwrap();
+
+    let servers = records.servers();
+    assert_eq!(servers.len(), 2);
+    assert_eq!(servers[0].instance, "Snapcast");
+    assert_eq!(servers[0].host, "prod.local");
+    assert_eq!(
+        servers[0].addr,
+        "10.0.0.2:1704".parse::<SocketAddr>().unwrap()
+    );
+    assert_eq!(
+        servers[0].txt,
+        vec![("version".to_string(), "0.28.0".to_string())]
+    );
+    assert_eq!(
+        servers[1].addr,
+        "10.0.0.3:1804".parse::<SocketAddr>().unwrap()
+    );
+}
+
+#[test]
+fn follows_compressed_names() {
+    let mut p = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 1];
+    // the service name at offset 12, pointed to from then on
+    name(&mut p, mdns::SERVICE);
+    p.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0x11, 0x94]);
+    p.extend_from_slice(&[0, 7, 4]);
+    p.extend_from_slice(b"Prod");
+    p.extend_from_slice(&[0xc0, 12]);
+    // SRV owner: "Prod" + pointer, target "prod.local" written out
+    let owner = p.len() - 7;
+    p.extend_from_slice(&[0xc0, owner as u8, 0, 33, 0x80, 1, 0, 0, 0x11, 0x94]);
+    let mut srv = vec![0, 0, 0, 0, 0x06, 0xa8];
+    name(&mut srv, "prod.local");
+    p.extend_from_slice(&(srv.len() as u16).to_be_bytes());
+    p.extend_from_slice(&srv);
+
+    let mut records = Records::default();
+    records.add(&p).unwrap();
+    // no A record yet: not a server
+    assert!(records.servers().is_empty());
+    records
+        .add(&{
+            let mut a = vec![0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0];
+            record(&mut a, "prod.local", 1, &[10, 0, 0, 2]);
+            a
+        })
+        .unwrap();
+    let servers = records.servers();
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].instance, "Prod");
+    assert_eq!(
+        servers[0].addr,
+        "10.0.0.2:1704".parse::<SocketAddr>().unwrap()
+    );
+}
+
+#[test]
+fn instance_names_ignore_case() {
+    let mut p = vec![0, 0, 0x84, 0, 0, 0, 0, 5, 0, 0, 0, 0];
+    let mut ptr = Vec::new();
+    name(&mut ptr, "Prod._SNAPCAST._TCP.local");
+    record(&mut p, "_Snapcast._tcp.local", 12, &ptr);
+    // not the service, however it ends up with an SRV and A record
+    let mut other = Vec::new();
+    name(&mut other, "Other._http._tcp.local");
+    record(&mut p, mdns::SERVICE, 12, &other);
+    for owner in ["Prod._SNAPCAST._TCP.local", "Other._http._tcp.local"] {
+        let mut srv = vec![0, 0, 0, 0, 0x06, 0xa8];
+        name(&mut srv, "prod.local");
+        record(&mut p, owner, 33, &srv);
+    }
+    record(&mut p, "prod.local", 1, &[10, 0, 0, 2]);
+
+    let mut records = Records::default();
+    records.add(&p).unwrap();
+    let servers = records.servers();
+    assert_eq!(servers.len(), 1);
+    assert_eq!(servers[0].instance, "Prod");
+}
+
+#[test]
+fn truncated_packets_are_refused() {
+    let p = response("Snapcast", "prod.local", [10, 0, 0, 2], 1704, &[]);
+    let mut records = Records::default();
+    assert!(records.add(&p[..p.len() - 2]).is_none());
+    // a pointer loop
+    let looped = [0, 0, 0x84, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0xc0, 12];
+    assert!(records.add(&looped).is_none());
+}
+
+#[test]
+fn selects_by_name_then_last_then_instance() {
+    let servers = [
+        server("Test", "test.local", "10.0.0.3:1704"),
+        server("Snapcast", "prod.local", "10.0.0.2:1704"),
+    ];
+    let names = |n: &[&str]| n.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+    let pick = mdns::select(&servers, &names(&["missing", "prod"]), Some("Test"));
+    assert_eq!(pick.unwrap().instance, "Snapcast");
+    let pick = mdns::select(&servers, &names(&["TEST"]), None);
+    assert_eq!(pick.unwrap().instance, "Test");
+    // only the named servers are taken
+    assert!(mdns::select(&servers, &names(&["other"]), Some("Test")).is_none());
+
+    assert_eq!(
+        mdns::select(&servers, &[], Some("Test")).unwrap().instance,
+        "Test"
+    );
+    assert_eq!(
+        mdns::select(&servers, &[], Some("test")).unwrap().instance,
+        "Test"
+    );
+    assert_eq!(
+        mdns::select(&servers, &[], Some("gone")).unwrap().instance,
+        "Snapcast"
+    );
+    assert!(mdns::select(&[], &[], None).is_none());
+}
+
+#[test]
+fn mode_round_trips() {
+    for mode in [mdns::Mode::Off, mdns::Mode::Primary, mdns::Mode::Fallback] {
+        assert_eq!(mode.as_str().parse::<mdns::Mode>().unwrap(), mode);
+    }
+    assert!("on".parse::<mdns::Mode>().is_err());
+}
@@ -0,0 +1,72 @@
+use esp_snapcast_sim::reconnect::{Backoff, Failure, Supervisor, STABLE};
+
+use std::time::Duration;
+
+#[test]
+fn backoff_doubles_up_to_max_with_jitter() {
+    let mut b = Backoff::new(Duration::from_secs(1), Duration::from_secs(8));
+    let lowest: Vec<u64> = (0..6).map(|_| b.next(0).as_millis() as u64).collect();
+    assert_eq!(lowest, [500, 1000, 2000, 4000, 4000, 4000]);
+
+    b.reset();
+    let highest: Vec<u64> = (0..6)
+        .map(|_| b.next(u32::MAX).as_millis() as u64)
+        .collect();
+    assert_eq!(highest, [999, 1999, 3999, 7999, 7999, 7999]);
+}
+
+#[test]
+fn reboots_after_consecutive_failures() {
+    let mut s = Supervisor::new(3);
+    assert!(s.failed(Failure::Connect, 0).is_some());
+    assert!(s.failed(Failure::Connect, 0).is_some());
+    assert_eq!(s.failed(Failure::Connect, 0), None);
+    assert_eq!(s.consecutive(), 3);
+
+    let mut never = Supervisor::new(0);
+    for _ in 0..1000 {
+        assert!(never.failed(Failure::Connect, 0).is_some());
+    }
+}
+
+#[test]
+fn discovery_failures_only_back_off() {
+    let mut s = Supervisor::new(2);
+    let waits: Vec<u64> = (0..4)
+        .map(|_| s.failed(Failure::Discovery, 0).unwrap().as_millis() as u64)
+        .collect();
+    assert_eq!(waits, [500, 1000, 2000, 4000]);
+    assert_eq!(s.consecutive(), 0);
+    assert!(s.failed(Failure::Connect, 0).is_some());
+}
+
+#[test]
+fn connecting_resets_the_count() {
+    let mut s = Supervisor::new(3);
+    s.failed(Failure::Connect, 0);
+    s.failed(Failure::Connect, 0);
+    s.connected();
+    assert_eq!(s.consecutive(), 0);
+
+    // a short-lived connection still backs off further
+    let short = Failure::Protocol {
+        connected_for: Duration::from_secs(5),
+    };
+    assert_eq!(s.failed(short, 0), Some(Duration::from_secs(2)));
+    assert_eq!(s.consecutive(), 1);
+}
+
+#[test]
+fn stable_connection_resets_backoff() {
+    let mut s = Supervisor::new(3);
+    s.failed(Failure::Connect, 0);
+    let slow = s.failed(Failure::Connect, 0).unwrap();
+    assert_eq!(slow, Duration::from_secs(1));
+
+    s.connected();
+    let dropped = Failure::Protocol {
+        connected_for: STABLE,
+    };
+    assert_eq!(s.failed(dropped, 0), Some(Duration::from_millis(500)));
+    assert_eq!(s.consecutive(), 1);
+}
@@ -0,0 +1,69 @@
+use esp_snapcast_sim::resample::Resampler;
+use esp_snapcast_sim::CHANNELS;
+
+use std::f64::consts::PI;
+
+const AMPLITUDE: f64 = 16_000.0;
+
+fn sine(freq: f64, rate: u32, frame: f64) -> f64 {
+    AMPLITUDE * (2.0 * PI * freq * frame / f64::from(rate)).sin()
+}
+
+/// Resamples `frames` of a sine in chunks of `chunk` frames
+fn resample(from: u32, to: u32, freq: f64, frames: usize, chunk: usize) -> Vec<i16> {
+    let mut r = Resampler::new(CHANNELS);
+    assert!(!r.set_rates(from, to));
+    let input: Vec<i16> = (0..frames)
+        .flat_map(|i| [sine(freq, from, i as f64).round() as i16; CHANNELS])
+        .collect();
+    let mut output = Vec::new();
+    for c in input.chunks(chunk * CHANNELS) {
+        let mut out = vec![0; r.max_output(c.len())];
+        let n = r.process(c, &mut out);
+        output.extend_from_slice(&out[..n]);
+    }
+    output
+}
+
+#[test]
+fn same_rate_is_left_alone() {
+    let mut r = Resampler::new(CHANNELS);
+    assert!(r.set_rates(48_000, 48_000));
+}
+
+#[test]
+fn keeps_the_pitch_and_the_length() {
+    for (from, to) in [(44_100, 48_000), (48_000, 44_100)] {
+        for chunk in [1, 2, 441, 1152] {
+            let out = resample(from, to, 1000.0, from as usize, chunk);
+            let frames = out.len() / CHANNELS;
+            // a second in, a second out, the first two frames being the delay
+            let expected = to as usize;
+            assert!(
+                frames.abs_diff(expected) <= 1,
+                "{from}->{to} in {chunk}: {frames} frames"
+            );
+
+            // output frame n is input frame n * from / to, two frames late
+            let step = f64::from(from) / f64::from(to);
+            let worst = out
+                .chunks(CHANNELS)
+                .enumerate()
+                .skip(4)
+                .map(|(n, f)| {
+                    assert_eq!(f[0], f[1]);
+                    (f64::from(f[0]) - sine(1000.0, from, n as f64 * step - 2.0)).abs()
+                })
+                .fold(0.0, f64::max);
+            assert!(worst < AMPLITUDE * 0.001, "{from}->{to}: off by {worst}");
+        }
+    }
+}
+
+#[test]
+fn high_frequencies_survive() {
+    let out = resample(44_100, 48_000, 15_000.0, 44_100, 1152);
+    let peak = out[1000..].iter().map(|s| s.unsigned_abs()).max().unwrap();
+    // cubic interpolation rolls off towards Nyquist, but not by much
+    assert!(f64::from(peak) > AMPLITUDE * 0.8, "peak {peak}");
+}
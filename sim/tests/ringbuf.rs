@@ -0,0 +1,152 @@
+use esp_snapcast_sim::ringbuf::{ChunkRing, Popped, PushError, HEADER_LEN};
+use snapcast_client::proto::TimeVal;
+
+use std::time::Duration;
+
+fn ts(ms: i32) -> TimeVal {
+    TimeVal {
+        sec: ms / 1000,
+        usec: ms % 1000 * 1000,
+    }
+}
+
+/// `len` bytes counting up from `seed`
+fn payload(seed: u8, len: usize) -> Vec<u8> {
+    (0..len).map(|i| seed.wrapping_add(i as u8)).collect()
+}
+
+#[test]
+fn chunks_straddle_the_end() {
+    // 100 does not divide the positions' range, and no entry size divides 100
+    let mut ring = ChunkRing::new(100, 40);
+    let (mut producer, mut consumer) = ring.split();
+    let mut out = [0u8; 40];
+    for i in 0..10_000 {
+        let len = 1 + i % 37;
+        let sent = payload(i as u8, len);
+        producer.push(ts(i as i32), &sent).unwrap();
+        if i % 2 == 1 {
+            // keep a second entry queued, so that the two wrap independently
+            producer.push(ts(i as i32), &sent[..len / 2]).unwrap();
+        }
+        let Popped::Chunk(at, got) = consumer.pop(&mut out, Duration::ZERO) else {
+            panic!("chunk {i} missing");
+        };
+        assert_eq!(at, ts(i as i32));
+        assert_eq!(got, &sent[..]);
+        if i % 2 == 1 {
+            let Popped::Chunk(_, got) = consumer.pop(&mut out, Duration::ZERO) else {
+                panic!("half chunk {i} missing");
+            };
+            assert_eq!(got, &sent[..len / 2]);
+        }
+        assert_eq!(consumer.fill_bytes(), 0);
+    }
+}
+
+#[test]
+fn push_refuses_what_does_not_fit() {
+    let mut ring = ChunkRing::new(64, 20);
+    let (mut producer, mut consumer) = ring.split();
+    assert!(matches!(
+        producer.push(ts(0), &[0; 21]),
+        Err(PushError::TooLarge)
+    ));
+    // two entries of 32 bytes fill it exactly
+    producer.push(ts(0), &[1; 20]).unwrap();
+    producer.push(ts(20), &[2; 20]).unwrap();
+    assert_eq!(consumer.fill_bytes(), 2 * (HEADER_LEN + 20));
+    assert!(matches!(producer.push(ts(40), &[]), Err(PushError::Full)));
+
+    let mut out = [0u8; 20];
+    assert!(matches!(
+        consumer.pop(&mut out, Duration::ZERO),
+        Popped::Chunk(_, [1, ..])
+    ));
+    producer.push(ts(40), &[3; 20]).unwrap();
+}
+
+#[test]
+fn fill_drops_to_zero_when_empty() {
+    let mut ring = ChunkRing::new(256, 32);
+    let (mut producer, mut consumer) = ring.split();
+    for i in 0..3 {
+        producer.push(ts(i * 20), &[0; 32]).unwrap();
+    }
+    assert_eq!(consumer.fill_bytes(), 3 * (HEADER_LEN + 32));
+    assert_eq!(consumer.fill_ms(), 40);
+
+    let mut out = [0u8; 32];
+    for _ in 0..3 {
+        assert!(matches!(
+            consumer.pop(&mut out, Duration::ZERO),
+            Popped::Chunk(..)
+        ));
+    }
+    assert_eq!(consumer.fill_bytes(), 0);
+    assert_eq!(consumer.fill_ms(), 0);
+    assert_eq!(producer.fill_ms(), 0);
+    assert!(matches!(
+        consumer.pop(&mut out, Duration::from_millis(1)),
+        Popped::Timeout
+    ));
+}
+
+#[test]
+fn dropped_producer_closes() {
+    let mut ring = ChunkRing::new(256, 32);
+    let (mut producer, mut consumer) = ring.split();
+    producer.push(ts(0), &[0; 32]).unwrap();
+    drop(producer);
+    // what is left belongs to a dead connection
+    let mut out = [0u8; 32];
+    assert!(matches!(
+        consumer.pop(&mut out, Duration::from_secs(1)),
+        Popped::Closed
+    ));
+}
+
+#[test]
+fn closes_a_waiting_consumer() {
+    let mut ring = ChunkRing::new(256, 32);
+    let (producer, mut consumer) = ring.split();
+    std::thread::scope(|s| {
+        s.spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            drop(producer);
+        });
+        let mut out = [0u8; 32];
+        assert!(matches!(
+            consumer.pop(&mut out, Duration::from_secs(10)),
+            Popped::Closed
+        ));
+    });
+}
+
+#[test]
+fn split_starts_over() {
+    let mut ring = ChunkRing::new(100, 40);
+    {
+        let (mut producer, mut consumer) = ring.split();
+        producer.push(ts(1000), &[1; 40]).unwrap();
+        producer.push(ts(1020), &[2; 30]).unwrap();
+        let mut out = [0u8; 40];
+        consumer.pop(&mut out, Duration::ZERO);
+    }
+
+    let (mut producer, mut consumer) = ring.split();
+    assert_eq!(consumer.fill_bytes(), 0);
+    assert_eq!(consumer.fill_ms(), 0);
+    let mut out = [0u8; 40];
+    assert!(matches!(
+        consumer.pop(&mut out, Duration::ZERO),
+        Popped::Timeout
+    ));
+    // the whole capacity is free again
+    producer.push(ts(0), &[3; 40]).unwrap();
+    producer.push(ts(20), &[4; 36]).unwrap();
+    assert!(matches!(
+        consumer.pop(&mut out, Duration::ZERO),
+        Popped::Chunk(at, [3, ..]) if at == ts(0)
+    ));
+}
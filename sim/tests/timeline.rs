@@ -0,0 +1,330 @@
+use esp_snapcast_sim::sched::{Decision, Outcome};
+use esp_snapcast_sim::{stream, Chunk, FakeClock, RecordingPlayer, Sim, Step, Write, RATE};
+
+use std::time::Duration;
+
+const LATENCY: Duration = Duration::from_millis(100);
+
+fn sim() -> Sim {
+    Sim::new(RecordingPlayer::new(FakeClock::default(), LATENCY))
+}
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+fn frames_to_us(frames: usize) -> i64 {
+    frames as i64 * 1_000_000 / i64::from(RATE)
+}
+
+/// How far the write's first frame is from when the server wants it audible,
+/// positive = late
+fn error_us(sim: &Sim, w: &Write, chunks: &[Chunk]) -> i64 {
+    let chunk = chunks.iter().find(|c| c.tag == w.tag).unwrap();
+    let due = chunk.audible_at.as_micros() as i64 - i64::from(sim.client_latency_ms) * 1000
+        + frames_to_us(w.first_frame as usize);
+    w.at.as_micros() as i64 - due
+}
+
+/// Whatever was played of every chunk reached the DAC within `tolerance_us`
+/// of its time
+fn assert_in_sync(sim: &Sim, chunks: &[Chunk], tolerance_us: i64) {
+    // tag 0 is silence
+    for w in sim.player.writes.iter().filter(|w| w.tag != 0) {
+        let err = error_us(sim, w, chunks);
+        assert!(
+            err.abs() <= tolerance_us,
+            "chunk {} is {err}us off: {w:?}",
+            w.tag
+        );
+    }
+}
+
+/// Audio never overlaps and never goes back in time
+fn assert_monotonic(sim: &Sim) {
+    for pair in sim.player.writes.windows(2) {
+        let end = pair[0].at.as_micros() as i64 + frames_to_us(pair[0].frames);
+        let next = pair[1].at.as_micros() as i64;
+        // a write spans up to 8s: allow for a 100ppm crystal over that
+        assert!(next >= end - 1000, "{:?} overlaps {:?}", pair[1], pair[0]);
+    }
+}
+
+fn played(steps: &[Step]) -> usize {
+    steps
+        .iter()
+        .filter(|s| matches!(s.outcome, Some(Outcome::Played { .. })))
+        .count()
+}
+
+#[test]
+fn plays_back_to_back_on_time() {
+    let mut sim = sim();
+    let chunks = stream(Duration::ZERO, 500, 20, 1000);
+    let steps = sim.feed_all(&chunks);
+
+    // the first chunk is ~900ms early: silence until it is due
+    assert!(matches!(
+        steps[0].outcome,
+        Some(Outcome::Played {
+            padded_frames,
+            skipped_frames: 0,
+        }) if padded_frames == 900 * 48
+    ));
+    for s in &steps[1..] {
+        assert!(matches!(s.decision, Decision::Locked { .. }), "{s:?}");
+    }
+    assert_eq!(played(&steps), chunks.len());
+    assert_in_sync(&sim, &chunks, 100);
+    assert_monotonic(&sim);
+}
+
+#[test]
+fn client_latency_plays_earlier() {
+    let mut sim = sim();
+    sim.client_latency_ms = 30;
+    let chunks = stream(Duration::ZERO, 100, 20, 1000);
+    sim.feed_all(&chunks);
+
+    let first = sim.writes_of(1).next().unwrap();
+    assert_eq!(first.at, ms(1000 - 30));
+    assert_in_sync(&sim, &chunks, 100);
+}
+
+#[test]
+fn late_start_is_trimmed() {
+    let mut sim = sim();
+    // popped 5ms after it had to be written
+    let chunks = stream(Duration::ZERO, 100, 20, 95);
+    let steps = sim.feed_all(&chunks);
+
+    assert_eq!(steps[0].decision, Decision::HardSync { offset_us: -5000 });
+    assert_eq!(
+        steps[0].outcome,
+        Some(Outcome::Played {
+            padded_frames: 0,
+            skipped_frames: 240,
+        })
+    );
+    let first = sim.writes_of(1).next().unwrap();
+    assert_eq!(first.first_frame, 240);
+    assert!(matches!(steps[1].decision, Decision::Locked { .. }));
+    assert_in_sync(&sim, &chunks, 100);
+}
+
+#[test]
+fn chunk_more_than_a_second_late_is_dropped() {
+    let mut sim = sim();
+    let late = Chunk {
+        arrival: ms(1200),
+        audible_at: Duration::ZERO,
+        frames: 960,
+        tag: 1,
+    };
+    let step = sim.feed(&late);
+    assert_eq!(step.decision, Decision::TooLate);
+    assert_eq!(step.outcome, None);
+    assert!(sim.player.writes.is_empty());
+
+    // the stream carries on
+    let chunks = stream(ms(1200), 10, 20, 500);
+    let steps = sim.feed_all(&chunks);
+    assert_eq!(played(&steps), 10);
+}
+
+#[test]
+fn bogus_timestamp_far_ahead_is_dropped() {
+    let mut sim = sim();
+    let bogus = Chunk {
+        arrival: Duration::ZERO,
+        audible_at: Duration::from_secs(10),
+        frames: 960,
+        tag: 1,
+    };
+    assert_eq!(sim.feed(&bogus).decision, Decision::TooFar);
+    assert!(sim.player.writes.is_empty());
+}
+
+#[test]
+fn sub_second_lateness_is_skipped() {
+    let mut sim = sim();
+    // the server timestamp minus now is {sec: -1, usec: 700000}
+    let late = Chunk {
+        arrival: Duration::from_secs(1),
+        audible_at: ms(1000 + 100 - 300),
+        frames: 19_200,
+        tag: 1,
+    };
+    let step = sim.feed(&late);
+    assert_eq!(
+        step.decision,
+        Decision::HardSync {
+            offset_us: -300_000
+        }
+    );
+    assert_eq!(
+        step.outcome,
+        Some(Outcome::Played {
+            padded_frames: 0,
+            skipped_frames: 14_400,
+        })
+    );
+    assert_eq!(sim.player.writes[0].frames, 4800);
+}
+
+#[test]
+fn skipping_more_than_a_chunk_drops_it_whole() {
+    let mut sim = sim();
+    let mut chunks = stream(Duration::ZERO, 3, 20, 100);
+    // popped at once, the first one 50ms late
+    for c in &mut chunks {
+        c.arrival = ms(50);
+    }
+    let steps = sim.feed_all(&chunks);
+    assert_eq!(steps[0].outcome, Some(Outcome::SkippedWhole));
+    assert_eq!(steps[1].outcome, Some(Outcome::SkippedWhole));
+    assert!(matches!(
+        steps[2].outcome,
+        Some(Outcome::Played {
+            skipped_frames: 480,
+            ..
+        })
+    ));
+    assert_in_sync(&sim, &chunks, 100);
+}
+
+#[test]
+fn write_jitter_does_not_move_playback() {
+    let player = RecordingPlayer::new(FakeClock::default(), LATENCY).with_jitter(ms(10));
+    let mut sim = Sim::new(player);
+    // 1 minute
+    let chunks = stream(Duration::ZERO, 3000, 20, 1000);
+    let steps = sim.feed_all(&chunks);
+
+    for s in &steps[1..] {
+        assert!(matches!(s.decision, Decision::Locked { .. }), "{s:?}");
+    }
+    assert_in_sync(&sim, &chunks, 1000);
+    assert_monotonic(&sim);
+}
+
+fn drift(ppm: f64) {
+    let player = RecordingPlayer::new(FakeClock::default(), LATENCY)
+        .with_jitter(ms(10))
+        .with_ppm(ppm);
+    let mut sim = Sim::new(player);
+    // 10 minutes
+    let chunks = stream(Duration::ZERO, 30_000, 20, 1000);
+    let steps = sim.feed_all(&chunks);
+
+    for s in &steps[1..] {
+        assert!(matches!(s.decision, Decision::Locked { .. }), "{s:?}");
+    }
+    // uncorrected, 100ppm would be 60ms off by now
+    assert_in_sync(&sim, &chunks, 1000);
+    assert_monotonic(&sim);
+    let written: usize = sim.player.writes.iter().map(|w| w.frames).sum();
+    let sent: usize = chunks.iter().map(|c| c.frames).sum();
+    let padded = 900 * 48;
+    let corrected = written as i64 - sent as i64 - padded;
+    // ~2880 frames over 10 minutes
+    let expected = (sent as f64 * ppm / 1e6) as i64;
+    assert!(
+        (corrected - expected).abs() < 200,
+        "corrected {corrected} frames, expected ~{expected}"
+    );
+}
+
+#[test]
+fn fast_dac_gets_frames_inserted() {
+    drift(100.0);
+}
+
+#[test]
+fn slow_dac_gets_frames_dropped() {
+    drift(-100.0);
+}
+
+#[test]
+fn gap_in_the_stream_resyncs() {
+    let mut sim = sim();
+    let mut chunks = stream(Duration::ZERO, 50, 20, 1000);
+    // the stream pauses for 2s
+    let mut resumed = stream(ms(3000), 50, 20, 1000);
+    for c in &mut resumed {
+        c.tag += 50;
+    }
+    chunks.extend(resumed);
+    let steps = sim.feed_all(&chunks);
+
+    assert!(matches!(steps[50].decision, Decision::HardSync { offset_us } if offset_us > 0));
+    assert!(matches!(steps[51].decision, Decision::Locked { .. }));
+    assert_eq!(played(&steps), 100);
+    assert_in_sync(&sim, &chunks, 100);
+    assert_monotonic(&sim);
+}
+
+#[test]
+fn late_burst_after_a_stall_catches_up() {
+    let mut sim = sim();
+    let mut chunks = stream(Duration::ZERO, 200, 20, 300);
+    // Wi-Fi stalls: chunks 100-119 arrive at once, 600ms after the first was sent
+    for c in &mut chunks[100..120] {
+        c.arrival = ms(2000 + 600);
+    }
+    let steps = sim.feed_all(&chunks);
+
+    // popped at 2.6s with a drained DMA ring: audible before 2.7s is lost
+    let lost = steps[100..120]
+        .iter()
+        .filter(|s| s.outcome == Some(Outcome::SkippedWhole))
+        .count();
+    assert_eq!(lost, 20);
+    assert!(matches!(steps[120].decision, Decision::HardSync { .. }));
+    assert_eq!(played(&steps), 180);
+    assert_in_sync(&sim, &chunks, 100);
+    assert_monotonic(&sim);
+}
+
+#[test]
+fn clock_step_forward_skips_once() {
+    let mut sim = sim();
+    let chunks = stream(Duration::ZERO, 200, 20, 1000);
+    sim.feed_all(&chunks[..100]);
+    sim.clock.step(50_000);
+    let steps = sim.feed_all(&chunks[100..]);
+
+    let skipped: usize = steps
+        .iter()
+        .map(|s| match s.outcome {
+            Some(Outcome::SkippedWhole) => 960,
+            Some(Outcome::Played { skipped_frames, .. }) => skipped_frames,
+            None | Some(Outcome::FadedOut) => 0,
+        })
+        .sum();
+    assert!((2350..=2450).contains(&skipped), "skipped {skipped} frames");
+    let resyncs = steps
+        .iter()
+        .filter(|s| matches!(s.decision, Decision::HardSync { .. }))
+        .count();
+    assert_eq!(resyncs, 3);
+    assert_in_sync(&sim, &chunks, 100);
+}
+
+#[test]
+fn clock_step_backward_pads_once() {
+    let mut sim = sim();
+    let chunks = stream(Duration::ZERO, 200, 20, 1000);
+    sim.feed_all(&chunks[..100]);
+    sim.clock.step(-50_000);
+    let steps = sim.feed_all(&chunks[100..]);
+
+    assert!(matches!(
+        steps[0].outcome,
+        Some(Outcome::Played { padded_frames, .. }) if (2350..=2450).contains(&padded_frames)
+    ));
+    for s in &steps[1..] {
+        assert!(matches!(s.decision, Decision::Locked { .. }), "{s:?}");
+    }
+    assert_in_sync(&sim, &chunks, 100);
+}
@@ -0,0 +1,78 @@
+use esp_snapcast_sim::ramp::UNITY;
+use esp_snapcast_sim::volume::{Curve, VolumeCurve};
+
+#[test]
+fn samples_default_to_the_square_curve() {
+    let curve = VolumeCurve::default();
+    assert_eq!(curve.gain(0), 0);
+    // 1 is the bottom of the curve, as before curves could be picked
+    assert_eq!(curve.gain(1), 0);
+    assert_eq!(curve.gain(100), UNITY);
+    for vol in 1..=100u8 {
+        let x = (f64::from(vol) - 1.0) / 99.0;
+        let expected = (x * x * f64::from(UNITY)).round() as i32;
+        assert_eq!(curve.gain(vol), expected, "volume {vol}");
+    }
+}
+
+#[test]
+fn codecs_default_to_equal_db_steps() {
+    let curve = VolumeCurve::default();
+    assert_eq!(curve.attenuation_half_db(0), None);
+    for vol in 1..=100u8 {
+        let expected = (100 - u32::from(vol)) * 60 * 2 / 99;
+        assert_eq!(
+            curve.attenuation_half_db(vol),
+            Some(expected),
+            "volume {vol}"
+        );
+    }
+}
+
+#[test]
+fn db_curve_spans_the_range() {
+    let curve = VolumeCurve {
+        curve: Some(Curve::Db),
+        range_db: 40,
+        max: 100,
+    };
+    assert_eq!(curve.attenuation_half_db(1), Some(80));
+    assert_eq!(curve.attenuation_half_db(100), Some(0));
+    // -40dB is a hundredth of full scale
+    assert_eq!(curve.gain(1), (f64::from(UNITY) / 100.0).round() as i32);
+    assert_eq!(curve.gain(100), UNITY);
+}
+
+#[test]
+fn picked_curve_applies_to_codecs_too() {
+    let curve = VolumeCurve {
+        curve: Some(Curve::Linear),
+        ..VolumeCurve::default()
+    };
+    assert_eq!(curve.attenuation_half_db(1), None);
+    assert_eq!(
+        curve.gain(50),
+        (49.0 / 99.0 * f64::from(UNITY)).round() as i32
+    );
+    assert_eq!(curve.attenuation_half_db(100), Some(0));
+    // about half the amplitude, 6dB down
+    assert_eq!(curve.attenuation_half_db(50), Some(12));
+}
+
+#[test]
+fn max_caps_the_top() {
+    let curve = VolumeCurve {
+        curve: Some(Curve::Linear),
+        range_db: 60,
+        max: 50,
+    };
+    assert_eq!(curve.gain(100), UNITY / 2);
+    assert!(curve.gain(50) < curve.gain(100));
+    let db = VolumeCurve {
+        curve: Some(Curve::Db),
+        range_db: 60,
+        max: 50,
+    };
+    // halfway along a 60dB range
+    assert_eq!(db.attenuation_half_db(100), Some(60));
+}
@@ -0,0 +1,48 @@
+use esp_idf_hal::gpio::{AnyIOPin, Level, Output, PinDriver};
+
+use std::time::Duration;
+
+/// An amplifier's enable (or mute) input. It is only enabled while I2S is
+/// clocking, so that the amplifier never sees the DAC start or stop.
+pub struct Amp {
+    pin: PinDriver<'static, AnyIOPin, Output>,
+    active_low: bool,
+    on: bool,
+    /// How long the amplifier needs before it plays without a pop
+    pub lead: Duration,
+    /// Without audio for this long, it is disabled
+    pub idle: Option<Duration>,
+}
+
+impl Amp {
+    /// Takes the pin and disables the amplifier right away
+    pub fn new(
+        pin: AnyIOPin,
+        active_low: bool,
+        lead: Duration,
+        idle: Option<Duration>,
+    ) -> anyhow::Result<Amp> {
+        let mut amp = Amp {
+            pin: PinDriver::output(pin)?,
+            active_low,
+            on: true,
+            lead,
+            idle,
+        };
+        amp.set(false)?;
+        Ok(amp)
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.on
+    }
+
+    pub fn set(&mut self, on: bool) -> anyhow::Result<()> {
+        if on != self.on {
+            log::info!("amplifier {}", if on { "on" } else { "off" });
+        }
+        self.pin.set_level(Level::from(on != self.active_low))?;
+        self.on = on;
+        Ok(())
+    }
+}
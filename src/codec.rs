@@ -0,0 +1,148 @@
+use snapcast_client::decoder::{Decoder, FlacState};
+use snapcast_client::opus_embedded;
+use snapcast_client::proto::{CodecHeader, CodecMetadata};
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Interleaved samples the decode buffer must hold:
+/// >= 5760 for OPUS (60ms max frame @48k stereo)
+/// >= 3840 for PCM (40ms chunks @48k stereo)
+/// >= 4700 for flac
+pub const DEC_SAMPLES: usize = 5760;
+
+/// Decoded audio is interleaved 16 bit stereo
+pub const CHANNELS: usize = 2;
+
+/// snapserver's `chunk_ms` is not announced to clients; PCM chunks are sized
+/// assuming the recommended 40ms at most, at up to 48kHz
+const PCM_MAX_CHUNK_MS: usize = 40;
+const PCM_MAX_RATE: usize = 48_000;
+
+// Storage for decoder state: living in .bss means it is accounted at link time,
+// cannot fail to allocate and cannot fragment the heap.
+// handed_out pairs with the Decoder's lifetime: claim on Decoder::new, release
+// only after the borrowing Decoder has been dropped.
+struct Slot<T> {
+    state: UnsafeCell<MaybeUninit<T>>,
+    handed_out: AtomicBool,
+}
+
+// SAFETY: handed_out serializes access
+unsafe impl<T> Sync for Slot<T> {}
+
+impl<T> Slot<T> {
+    const fn new() -> Slot<T> {
+        Slot {
+            state: UnsafeCell::new(MaybeUninit::uninit()),
+            handed_out: AtomicBool::new(false),
+        }
+    }
+
+    #[allow(clippy::mut_from_ref)] // handed_out guarantees exclusivity at runtime
+    fn claim(&'static self) -> &'static mut MaybeUninit<T> {
+        let was_handed_out = self.handed_out.swap(true, Ordering::AcqRel);
+        assert!(!was_handed_out, "decoder slot is still borrowed");
+        // SAFETY: handed_out guarantees this is the only live borrow
+        unsafe { &mut *self.state.get() }
+    }
+
+    fn release(&self) {
+        self.handed_out.store(false, Ordering::Release);
+    }
+}
+
+// ~26.6KiB of opus decoder state
+static OPUS_SLOT: Slot<opus_embedded::Decoder> = Slot::new();
+// the FLAC decoder's per-channel block buffers
+static FLAC_SLOT: Slot<FlacState> = Slot::new();
+
+/// The decoder borrows a static slot; the old one must be dropped (see
+/// `drop_decoder`) before a new one can claim it.
+/// PCM decoding is a copy and has no state to account for.
+pub fn new_decoder(ch: &CodecHeader) -> anyhow::Result<Decoder> {
+    match &ch.metadata {
+        CodecMetadata::Opus(cfg) => Decoder::new_opus(cfg, OPUS_SLOT.claim()).map_err(|e| {
+            OPUS_SLOT.release();
+            e
+        }),
+        CodecMetadata::Flac(cfg) => Decoder::new_flac(cfg, FLAC_SLOT.claim()).map_err(|e| {
+            FLAC_SLOT.release();
+            e
+        }),
+        CodecMetadata::Pcm(cfg) => {
+            // its chunks are sized by the rate, and copied as they are
+            anyhow::ensure!(
+                usize::from(cfg.channel_count) == CHANNELS,
+                "PCM with {} channels, only stereo plays",
+                cfg.channel_count
+            );
+            let rate = ch.metadata.rate() as usize;
+            anyhow::ensure!(
+                rate <= PCM_MAX_RATE,
+                "PCM at {rate}Hz does not fit the decode buffer, {PCM_MAX_RATE}Hz at most"
+            );
+            Decoder::new_pcm(cfg)
+        }
+    }
+}
+
+/// Drops the decoder, then releases the slot it borrowed
+pub fn drop_decoder(dec: &mut Option<Decoder>) {
+    let Some(d) = dec.take() else {
+        return;
+    };
+    // only the slot of this decoder: another one may be claimed already
+    let release: fn() = match d {
+        Decoder::Opus { .. } => || OPUS_SLOT.release(),
+        Decoder::Flac { .. } => || FLAC_SLOT.release(),
+        Decoder::PCM { .. } => || {},
+    };
+    drop(d);
+    release();
+}
+
+pub fn name(meta: &CodecMetadata) -> &'static str {
+    match meta {
+        CodecMetadata::Opus(_) => "opus",
+        CodecMetadata::Flac(_) => "flac",
+        CodecMetadata::Pcm(_) => "pcm",
+    }
+}
+
+/// Worst-case size of a single chunk of each codec: (encoded bytes, decoded
+/// samples)
+const CHUNK_LIMITS: [(usize, usize); 3] = [
+    // opus: 1275B is the largest packet
+    (1275, 5760),
+    // flac: chunks are 4-5KiB, up to 9KiB
+    (9 * 1024, 4700),
+    // pcm
+    (
+        PCM_MAX_RATE / 1000 * PCM_MAX_CHUNK_MS * CHANNELS * 2,
+        PCM_MAX_RATE / 1000 * PCM_MAX_CHUNK_MS * CHANNELS,
+    ),
+];
+
+/// The largest encoded chunk of any codec
+pub const MAX_ENCODED_CHUNK: usize = {
+    let mut max = 0;
+    let mut i = 0;
+    while i < CHUNK_LIMITS.len() {
+        if CHUNK_LIMITS[i].0 > max {
+            max = CHUNK_LIMITS[i].0;
+        }
+        i += 1;
+    }
+    max
+};
+
+// a chunk of any codec decodes into the buffer
+const _: () = {
+    let mut i = 0;
+    while i < CHUNK_LIMITS.len() {
+        assert!(CHUNK_LIMITS[i].1 <= DEC_SAMPLES);
+        i += 1;
+    }
+};
@@ -0,0 +1,455 @@
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_sys::EspError;
+
+use crate::dac;
+use crate::eq::{self, Band};
+use crate::mdns;
+use crate::output::{self, OutputFormat};
+use crate::volume::VolumeCurve;
+
+const NAMESPACE: &str = "config";
+
+/// Bump when a key changes meaning, and teach `migrate` how to upgrade the
+/// previous layout. Keys that are merely added need no bump: missing keys read
+/// as their default.
+const SCHEMA_VERSION: u8 = 1;
+
+/// Per-device settings, stored in NVS so that every speaker can have its own
+/// name and wiring without a rebuild
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Client name shown in Snapweb
+    pub name: String,
+    pub dout_pin: u8,
+    pub bclk_pin: u8,
+    pub ws_pin: u8,
+    /// Master clock output, for DACs that need one
+    pub mclk_pin: Option<u8>,
+    /// MCLK frequency in multiples of the sample rate; None picks one that
+    /// suits the slot width
+    pub mclk_multiple: Option<u16>,
+    /// Codec chip that takes volume, mute and power over I2C; without one the
+    /// volume is applied to the samples
+    pub dac: Option<dac::Chip>,
+    /// None uses the chip's default address
+    pub dac_addr: Option<u8>,
+    pub i2c_sda_pin: u8,
+    pub i2c_scl_pin: u8,
+    /// Amplifier enable (or, active low, mute) output
+    pub amp_pin: Option<u8>,
+    pub amp_active_low: bool,
+    /// The amplifier is enabled this long before audio reaches it
+    pub amp_lead_ms: u16,
+    /// Disable the amplifier after this long without audio; 0 keeps it on
+    pub amp_idle_s: u16,
+    /// Snapserver IP or DNS name, used as `mdns` says
+    pub server_host: Option<String>,
+    pub server_port: u16,
+    pub mdns: mdns::Mode,
+    /// Only these mDNS instance or host names, in order of preference; empty
+    /// takes any server
+    pub server_names: Vec<String>,
+    /// Reboot after this many connection failures in a row; 0 never reboots
+    pub reboot_after_failures: u16,
+    /// Volume until the server sends its settings
+    pub start_volume: u8,
+    /// Volume changes are spread over this long
+    pub volume_ramp_ms: u16,
+    /// How the 0-100 volume maps onto a gain, and its cap
+    pub volume_curve: VolumeCurve,
+    /// Filters applied to the decoded audio, in order
+    pub eq: Vec<Band>,
+    /// Peaks above this many dBFS are limited; None disables the limiter
+    pub limiter_threshold_db: Option<i8>,
+    /// How fast the limiter's gain recovers
+    pub limiter_release_ms: u16,
+    pub output: OutputFormat,
+    /// The DMA sends zeroes when it runs dry; without it, it repeats its last
+    /// buffers until silence is written
+    pub dma_zero_fill: bool,
+    /// Play a quiet tone every few seconds while idle, to check the wiring
+    pub debug_tone: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            name: "esp32".into(),
+            dout_pin: 19,
+            bclk_pin: 18,
+            ws_pin: 21,
+            mclk_pin: None,
+            mclk_multiple: None,
+            dac: None,
+            dac_addr: None,
+            i2c_sda_pin: 33,
+            i2c_scl_pin: 32,
+            amp_pin: None,
+            amp_active_low: false,
+            amp_lead_ms: 100,
+            amp_idle_s: 30,
+            server_host: None,
+            server_port: 1704,
+            mdns: mdns::Mode::Primary,
+            server_names: Vec::new(),
+            reboot_after_failures: 10,
+            start_volume: 20,
+            volume_ramp_ms: 50,
+            volume_curve: VolumeCurve::default(),
+            eq: Vec::new(),
+            limiter_threshold_db: None,
+            limiter_release_ms: 100,
+            output: OutputFormat::default(),
+            dma_zero_fill: true,
+            debug_tone: false,
+        }
+    }
+}
+
+/// The ESP32 can only route I2S0's MCLK to the CLK_OUT pins
+fn parse_mclk_pin(value: &str) -> anyhow::Result<u8> {
+    let pin: u8 = value.parse()?;
+    anyhow::ensure!(
+        matches!(pin, 0 | 1 | 3),
+        "MCLK can only be on GPIO0, 1 or 3"
+    );
+    Ok(pin)
+}
+
+fn parse_pin(value: &str) -> anyhow::Result<u8> {
+    let pin: u8 = value.parse()?;
+    match pin {
+        // SPI flash
+        6..=11 => anyhow::bail!("GPIO{pin} is used by the flash"),
+        // input only
+        34..=39 => anyhow::bail!("GPIO{pin} can't output"),
+        40.. => anyhow::bail!("GPIO{pin} does not exist"),
+        _ => Ok(pin),
+    }
+}
+
+fn parse_names(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|n| !n.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl Config {
+    /// Reads the stored configuration; missing keys take their default value
+    pub fn load(nvs: &EspNvsPartition<NvsDefault>) -> Result<Config, EspError> {
+        let mut storage = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+        let mut cfg = Config::default();
+
+        match storage.get_u8("version")? {
+            None => {
+                log::info!("No stored configuration, using defaults");
+                return Ok(cfg);
+            }
+            Some(v) if v > SCHEMA_VERSION => {
+                // a downgrade: keys may mean something else now
+                log::warn!(
+                    "Configuration schema v{v} is newer than v{SCHEMA_VERSION}, using defaults"
+                );
+                return Ok(cfg);
+            }
+            Some(v) => migrate(&mut storage, v)?,
+        }
+
+        let mut buf = [0u8; 64];
+        if let Some(name) = storage.get_str("name", &mut buf)? {
+            cfg.name = name.into();
+        }
+        cfg.server_host = storage.get_str("srv_host", &mut buf)?.map(String::from);
+        if let Some(port) = storage.get_u16("srv_port")? {
+            cfg.server_port = port;
+        }
+        if let Some(mode) = storage.get_str("mdns", &mut buf)? {
+            cfg.mdns = mode.parse().unwrap_or(cfg.mdns);
+        }
+        if let Some(names) = storage.get_str("srv_names", &mut buf)? {
+            cfg.server_names = parse_names(names);
+        }
+        if let Some(n) = storage.get_u16("reboot_fails")? {
+            cfg.reboot_after_failures = n;
+        }
+        if let Some(pin) = storage.get_u8("pin_dout")? {
+            cfg.dout_pin = pin;
+        }
+        if let Some(pin) = storage.get_u8("pin_bclk")? {
+            cfg.bclk_pin = pin;
+        }
+        if let Some(pin) = storage.get_u8("pin_ws")? {
+            cfg.ws_pin = pin;
+        }
+        cfg.mclk_pin = storage.get_u8("pin_mclk")?;
+        cfg.mclk_multiple = storage.get_u16("mclk_mult")?;
+        if let Some(chip) = storage.get_str("dac", &mut buf)? {
+            cfg.dac = chip.parse().ok();
+        }
+        cfg.dac_addr = storage.get_u8("dac_addr")?;
+        if let Some(pin) = storage.get_u8("pin_sda")? {
+            cfg.i2c_sda_pin = pin;
+        }
+        if let Some(pin) = storage.get_u8("pin_scl")? {
+            cfg.i2c_scl_pin = pin;
+        }
+        cfg.amp_pin = storage.get_u8("pin_amp")?;
+        if let Some(low) = storage.get_u8("amp_low")? {
+            cfg.amp_active_low = low != 0;
+        }
+        if let Some(ms) = storage.get_u16("amp_lead")? {
+            cfg.amp_lead_ms = ms;
+        }
+        if let Some(s) = storage.get_u16("amp_idle")? {
+            cfg.amp_idle_s = s;
+        }
+        if let Some(vol) = storage.get_u8("start_vol")? {
+            cfg.start_volume = vol;
+        }
+        if let Some(ms) = storage.get_u16("vol_ramp")? {
+            cfg.volume_ramp_ms = ms;
+        }
+        if let Some(curve) = storage.get_str("vol_curve", &mut buf)? {
+            cfg.volume_curve.curve = curve.parse().ok();
+        }
+        if let Some(db) = storage
+            .get_u8("vol_range")?
+            .filter(|db| (10..=100).contains(db))
+        {
+            cfg.volume_curve.range_db = db;
+        }
+        if let Some(max) = storage
+            .get_u8("vol_max")?
+            .filter(|max| (1..=100).contains(max))
+        {
+            cfg.volume_curve.max = max;
+        }
+        // six bands of `highshelf:12000:-2.5:0.71`
+        let mut eq_buf = [0u8; 256];
+        if let Some(bands) = storage.get_str("eq", &mut eq_buf)? {
+            cfg.eq = eq::parse_bands(bands).unwrap_or_default();
+        }
+        cfg.limiter_threshold_db = storage.get_i8("lim_thresh")?;
+        if let Some(ms) = storage.get_u16("lim_release")? {
+            cfg.limiter_release_ms = ms;
+        }
+        if let Some(bits) = storage.get_u8("i2s_bits")? {
+            cfg.output.slot_bits = bits;
+        }
+        if let Some(framing) = storage.get_str("i2s_framing", &mut buf)? {
+            cfg.output.framing = framing.parse().unwrap_or(cfg.output.framing);
+        }
+        if let Some(channel) = storage.get_str("i2s_channel", &mut buf)? {
+            cfg.output.channel = channel.parse().unwrap_or(cfg.output.channel);
+        }
+        if let Some(fill) = storage.get_u8("zero_fill")? {
+            cfg.dma_zero_fill = fill != 0;
+        }
+        if let Some(tone) = storage.get_u8("debug_tone")? {
+            cfg.debug_tone = tone != 0;
+        }
+        Ok(cfg)
+    }
+
+    pub fn save(&self, nvs: &EspNvsPartition<NvsDefault>) -> Result<(), EspError> {
+        let mut storage = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+        storage.set_str("name", &self.name)?;
+        match &self.server_host {
+            Some(host) => storage.set_str("srv_host", host)?,
+            None => _ = storage.remove("srv_host")?,
+        }
+        storage.set_u16("srv_port", self.server_port)?;
+        storage.set_str("mdns", self.mdns.as_str())?;
+        storage.set_str("srv_names", &self.server_names.join(","))?;
+        storage.set_u16("reboot_fails", self.reboot_after_failures)?;
+        storage.set_u8("pin_dout", self.dout_pin)?;
+        storage.set_u8("pin_bclk", self.bclk_pin)?;
+        storage.set_u8("pin_ws", self.ws_pin)?;
+        match self.mclk_pin {
+            Some(pin) => storage.set_u8("pin_mclk", pin)?,
+            None => _ = storage.remove("pin_mclk")?,
+        }
+        match self.mclk_multiple {
+            Some(m) => storage.set_u16("mclk_mult", m)?,
+            None => _ = storage.remove("mclk_mult")?,
+        }
+        match self.dac {
+            Some(chip) => storage.set_str("dac", chip.as_str())?,
+            None => _ = storage.remove("dac")?,
+        }
+        match self.dac_addr {
+            Some(addr) => storage.set_u8("dac_addr", addr)?,
+            None => _ = storage.remove("dac_addr")?,
+        }
+        storage.set_u8("pin_sda", self.i2c_sda_pin)?;
+        storage.set_u8("pin_scl", self.i2c_scl_pin)?;
+        match self.amp_pin {
+            Some(pin) => storage.set_u8("pin_amp", pin)?,
+            None => _ = storage.remove("pin_amp")?,
+        }
+        storage.set_u8("amp_low", u8::from(self.amp_active_low))?;
+        storage.set_u16("amp_lead", self.amp_lead_ms)?;
+        storage.set_u16("amp_idle", self.amp_idle_s)?;
+        storage.set_u8("start_vol", self.start_volume)?;
+        storage.set_u16("vol_ramp", self.volume_ramp_ms)?;
+        match self.volume_curve.curve {
+            Some(curve) => storage.set_str("vol_curve", curve.as_str())?,
+            None => _ = storage.remove("vol_curve")?,
+        }
+        storage.set_u8("vol_range", self.volume_curve.range_db)?;
+        storage.set_u8("vol_max", self.volume_curve.max)?;
+        storage.set_str("eq", &eq::format_bands(&self.eq))?;
+        match self.limiter_threshold_db {
+            Some(db) => storage.set_i8("lim_thresh", db)?,
+            None => _ = storage.remove("lim_thresh")?,
+        }
+        storage.set_u16("lim_release", self.limiter_release_ms)?;
+        storage.set_u8("i2s_bits", self.output.slot_bits)?;
+        storage.set_str("i2s_framing", self.output.framing.as_str())?;
+        storage.set_str("i2s_channel", self.output.channel.as_str())?;
+        storage.set_u8("zero_fill", u8::from(self.dma_zero_fill))?;
+        storage.set_u8("debug_tone", u8::from(self.debug_tone))?;
+        // last: a partially written config keeps the old version and reads as such
+        storage.set_u8("version", SCHEMA_VERSION)?;
+        Ok(())
+    }
+
+    /// Sets a field from its textual (form) representation
+    pub fn set(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+        match key {
+            "name" => {
+                if value.is_empty() || value.len() > 63 {
+                    anyhow::bail!("name must be 1-63 bytes");
+                }
+                self.name = value.into();
+            }
+            "server_host" if value.is_empty() => self.server_host = None,
+            "server_host" => {
+                anyhow::ensure!(
+                    value.len() <= 63
+                        && value
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'.' || b == b'-'),
+                    "server host is an IP or a host name"
+                );
+                self.server_host = Some(value.into());
+            }
+            "server_port" => {
+                let port: u16 = value.parse()?;
+                anyhow::ensure!(port != 0, "server port is 1-65535");
+                self.server_port = port;
+            }
+            "mdns" => self.mdns = value.parse()?,
+            "server_names" => {
+                anyhow::ensure!(value.len() <= 63, "server names are at most 63 bytes");
+                self.server_names = parse_names(value);
+            }
+            "reboot_after_failures" => self.reboot_after_failures = value.parse()?,
+            "dout_pin" => self.dout_pin = parse_pin(value)?,
+            "bclk_pin" => self.bclk_pin = parse_pin(value)?,
+            "ws_pin" => self.ws_pin = parse_pin(value)?,
+            "mclk_pin" if value.is_empty() => self.mclk_pin = None,
+            "mclk_pin" => self.mclk_pin = Some(parse_mclk_pin(value)?),
+            "mclk_multiple" if value.is_empty() => self.mclk_multiple = None,
+            "mclk_multiple" => {
+                let m: u16 = value.parse()?;
+                anyhow::ensure!(
+                    matches!(m, 128 | 256 | 384),
+                    "MCLK multiple is 128, 256 or 384"
+                );
+                self.mclk_multiple = Some(m);
+            }
+            "dac" if value.is_empty() => self.dac = None,
+            "dac" => self.dac = Some(value.parse()?),
+            "dac_addr" if value.is_empty() => self.dac_addr = None,
+            "dac_addr" => {
+                let hex = value.trim_start_matches("0x");
+                let addr = u8::from_str_radix(hex, 16)?;
+                anyhow::ensure!((0x08..0x78).contains(&addr), "I2C address is 0x08-0x77");
+                self.dac_addr = Some(addr);
+            }
+            "i2c_sda_pin" => self.i2c_sda_pin = parse_pin(value)?,
+            "i2c_scl_pin" => self.i2c_scl_pin = parse_pin(value)?,
+            "amp_pin" if value.is_empty() => self.amp_pin = None,
+            "amp_pin" => self.amp_pin = Some(parse_pin(value)?),
+            "amp_active" => {
+                self.amp_active_low = match value {
+                    "high" => false,
+                    "low" => true,
+                    _ => anyhow::bail!("amp_active is high or low"),
+                }
+            }
+            "amp_lead_ms" => {
+                let ms: u16 = value.parse()?;
+                anyhow::ensure!(ms <= 2000, "amplifier lead is at most 2000ms");
+                self.amp_lead_ms = ms;
+            }
+            "amp_idle_s" => self.amp_idle_s = value.parse()?,
+            "start_volume" => {
+                let vol: u8 = value.parse()?;
+                anyhow::ensure!(vol <= 100, "volume is 0-100");
+                self.start_volume = vol;
+            }
+            "volume_ramp_ms" => {
+                let ms: u16 = value.parse()?;
+                anyhow::ensure!(ms <= 1000, "volume ramp is at most 1000ms");
+                self.volume_ramp_ms = ms;
+            }
+            "volume_curve" if value.is_empty() => self.volume_curve.curve = None,
+            "volume_curve" => self.volume_curve.curve = Some(value.parse()?),
+            "volume_range_db" => {
+                let db: u8 = value.parse()?;
+                anyhow::ensure!((10..=100).contains(&db), "volume range is 10-100dB");
+                self.volume_curve.range_db = db;
+            }
+            "max_volume" => {
+                let max: u8 = value.parse()?;
+                anyhow::ensure!((1..=100).contains(&max), "max volume is 1-100");
+                self.volume_curve.max = max;
+            }
+            "eq" => self.eq = eq::parse_bands(value)?,
+            "limiter_threshold_db" if value.is_empty() => self.limiter_threshold_db = None,
+            "limiter_threshold_db" => {
+                let db: i8 = value.parse()?;
+                anyhow::ensure!((-24..=0).contains(&db), "limiter threshold is -24 to 0dB");
+                self.limiter_threshold_db = Some(db);
+            }
+            "limiter_release_ms" => {
+                let ms: u16 = value.parse()?;
+                anyhow::ensure!((10..=2000).contains(&ms), "limiter release is 10-2000ms");
+                self.limiter_release_ms = ms;
+            }
+            "i2s_bits" => self.output.slot_bits = output::parse_slot_bits(value)?,
+            "i2s_framing" => self.output.framing = value.parse()?,
+            "i2s_channel" => self.output.channel = value.parse()?,
+            "i2s_zero_fill" => self.dma_zero_fill = value.parse()?,
+            "debug_tone" => self.debug_tone = value.parse()?,
+            _ => anyhow::bail!("unknown config key '{key}'"),
+        }
+        Ok(())
+    }
+}
+
+/// mDNS instance of the last server that accepted a connection; kept apart
+/// from the settings, which only change when saved from the portal
+pub fn last_server(nvs: &EspNvsPartition<NvsDefault>) -> Result<Option<String>, EspError> {
+    let storage = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut buf = [0u8; 64];
+    Ok(storage.get_str("last_server", &mut buf)?.map(String::from))
+}
+
+pub fn set_last_server(nvs: &EspNvsPartition<NvsDefault>, instance: &str) -> Result<(), EspError> {
+    let mut storage = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    storage.set_str("last_server", instance)
+}
+
+/// Upgrades keys written by an older schema in place
+fn migrate(_storage: &mut EspNvs<NvsDefault>, from: u8) -> Result<(), EspError> {
+    if from < SCHEMA_VERSION {
+        log::info!("Migrating configuration schema v{from} to v{SCHEMA_VERSION}");
+    }
+    Ok(())
+}
@@ -1,8 +1,11 @@
 use esp_idf_svc::sys::{uxTaskGetNumberOfTasks, uxTaskGetSystemState, TaskStatus_t};
 use std::collections::HashMap;
 use std::ffi::CStr;
+use std::sync::atomic::Ordering;
 use std::time::Duration;
 
+use crate::status;
+
 struct Snapshot {
     /// Total run time (esp_timer microseconds) at the moment of the snapshot.
     total: u64,
@@ -60,6 +63,16 @@ fn report(a: &Snapshot, b: &Snapshot) {
         idle("IDLE0"),
         idle("IDLE1"),
     );
+    for (core, name) in ["IDLE0", "IDLE1"].into_iter().enumerate() {
+        let pct = idle(name);
+        // u8::MAX: the IDLE task was not sampled
+        let free = if pct.is_nan() {
+            u8::MAX
+        } else {
+            pct.round() as u8
+        };
+        status::CPU_FREE[core].store(free, Ordering::Relaxed);
+    }
     for (pct, name) in rows.iter().take(10) {
         if *pct >= 1.0 && !name.starts_with("IDLE") {
             log::info!("  {name:>12}: {pct:5.1}%");
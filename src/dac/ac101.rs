@@ -0,0 +1,128 @@
+// X-Powers AC101, on the ESP32-A1S audio kit. That board does not route MCLK,
+// so SYSCLK comes from the PLL locked to BCLK1.
+// Registers are 8 bit addresses with 16 bit big endian values.
+
+use std::time::Duration;
+
+use crate::output::{Framing, OutputFormat};
+
+use super::{Bus, Dac};
+
+const CHIP_AUDIO_RS: u8 = 0x00;
+const PLL_CTRL1: u8 = 0x01;
+const PLL_CTRL2: u8 = 0x02;
+const SYSCLK_CTRL: u8 = 0x03;
+const MOD_CLK_ENA: u8 = 0x04;
+const MOD_RST_CTRL: u8 = 0x05;
+const I2S_SR_CTRL: u8 = 0x06;
+const I2S1LCK_CTRL: u8 = 0x10;
+const I2S1_SDOUT_CTRL: u8 = 0x11;
+const I2S1_SDIN_CTRL: u8 = 0x12;
+const I2S1_MXR_SRC: u8 = 0x13;
+const DAC_DIG_CTRL: u8 = 0x48;
+const DAC_MXR_SRC: u8 = 0x4c;
+const OMIXER_DACA_CTRL: u8 = 0x53;
+const OMIXER_SR: u8 = 0x54;
+const DAC_VOL_CTRL: u8 = 0x55;
+const HPOUT_CTRL: u8 = 0x56;
+const SPKOUT_CTRL: u8 = 0x58;
+
+/// OMIXER_DACA_CTRL: analog DACs and output mixers on
+const ANALOG_ON: u16 = 0xf080;
+/// DAC_VOL_CTRL: 0dB on both channels, -0.75dB steps
+const VOL_0DB: u16 = 0xa0;
+
+pub struct Ac101 {
+    bus: Bus,
+    volume: u16,
+    muted: bool,
+}
+
+impl Ac101 {
+    pub fn new(bus: Bus) -> Ac101 {
+        Ac101 {
+            bus,
+            volume: VOL_0DB,
+            muted: false,
+        }
+    }
+
+    fn write(&mut self, reg: u8, val: u16) -> anyhow::Result<()> {
+        let [hi, lo] = val.to_be_bytes();
+        self.bus.write(&[reg, hi, lo])
+    }
+
+    fn update_volume(&mut self) -> anyhow::Result<()> {
+        let vol = if self.muted { 0 } else { self.volume };
+        self.write(DAC_VOL_CTRL, (vol << 8) | vol)
+    }
+}
+
+impl Dac for Ac101 {
+    fn init(&mut self, format: &OutputFormat, rate: u32, _mclk: Option<u16>) -> anyhow::Result<()> {
+        let sample_rate = match rate {
+            44_100 => 0x7,
+            48_000 => 0x8,
+            96_000 => 0x9,
+            _ => anyhow::bail!("AC101 does not support {rate}Hz"),
+        };
+        let word_length = match format.slot_bits {
+            16 => 0b01,
+            24 => 0b11,
+            _ => anyhow::bail!("AC101 slots are at most 24 bit"),
+        };
+        let framing = match format.framing {
+            Framing::Philips => 0b00,
+            Framing::Msb => 0b01,
+            Framing::Pcm => 0b11,
+        };
+        self.write(CHIP_AUDIO_RS, 0x0123)?;
+        std::thread::sleep(Duration::from_millis(10));
+        // PLL from BCLK1, SYSCLK from the PLL
+        self.write(PLL_CTRL1, 0x014f)?;
+        self.write(PLL_CTRL2, 0x8600)?;
+        self.write(SYSCLK_CTRL, 0x8b08)?;
+        // I2S1 and DAC digital
+        self.write(MOD_CLK_ENA, 0x800c)?;
+        self.write(MOD_RST_CTRL, 0x800c)?;
+        self.write(I2S_SR_CTRL, sample_rate << 12)?;
+        // slave, BCLK/LRCK = 64, word length and format
+        let lck = 0x8040 | (word_length << 4) | (framing << 2);
+        self.write(I2S1LCK_CTRL, lck)?;
+        self.write(I2S1_SDOUT_CTRL, 0xc000)?;
+        self.write(I2S1_SDIN_CTRL, 0xc000)?;
+        self.write(I2S1_MXR_SRC, 0x2200)?;
+        self.write(DAC_MXR_SRC, 0xcc00)?;
+        self.write(DAC_DIG_CTRL, 0x8000)?;
+        // left DAC to the left mixer, right to the right
+        self.write(OMIXER_SR, 0x0102)?;
+        self.write(OMIXER_DACA_CTRL, 0)?;
+        self.update_volume()
+    }
+
+    fn set_attenuation(&mut self, half_db: Option<u32>) -> anyhow::Result<()> {
+        // 0.75dB steps
+        self.volume = half_db.map_or(0, |att| {
+            VOL_0DB - (att * 2 / 3).min(u32::from(VOL_0DB - 1)) as u16
+        });
+        self.update_volume()
+    }
+
+    fn set_muted(&mut self, muted: bool) -> anyhow::Result<()> {
+        self.muted = muted;
+        self.update_volume()
+    }
+
+    fn set_powered(&mut self, on: bool) -> anyhow::Result<()> {
+        if on {
+            self.write(OMIXER_DACA_CTRL, ANALOG_ON)?;
+            // headphone amp and speaker drivers on
+            self.write(HPOUT_CTRL, 0xfbc0)?;
+            self.write(SPKOUT_CTRL, 0xeabd)
+        } else {
+            self.write(SPKOUT_CTRL, 0xe880)?;
+            self.write(HPOUT_CTRL, 0x3c00)?;
+            self.write(OMIXER_DACA_CTRL, 0)
+        }
+    }
+}
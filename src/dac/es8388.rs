@@ -0,0 +1,101 @@
+// Everest ES8388, e.g. on the ESP32-LyraT and ESP32-A1S audio kits.
+// Runs as an I2S slave off MCLK (128, 256 or 384fs); the DACs feed LOUT1/ROUT1
+// (headphones) and LOUT2/ROUT2 (speaker amp) through the output mixers.
+
+use crate::output::{Framing, OutputFormat};
+
+use super::{Bus, Dac};
+
+const CONTROL1: u8 = 0x00;
+const CONTROL2: u8 = 0x01;
+const CHIPPOWER: u8 = 0x02;
+const MASTERMODE: u8 = 0x08;
+const DACPOWER: u8 = 0x04;
+const DACCONTROL1: u8 = 0x17;
+const DACCONTROL2: u8 = 0x18;
+const DACCONTROL3: u8 = 0x19;
+const DACCONTROL4: u8 = 0x1a;
+const DACCONTROL5: u8 = 0x1b;
+const DACCONTROL17: u8 = 0x27;
+const DACCONTROL20: u8 = 0x2a;
+const DACCONTROL21: u8 = 0x2b;
+const DACCONTROL24: u8 = 0x2e;
+
+/// DACCONTROL3: soft ramp, DACMute
+const DAC_RAMP: u8 = 0x20;
+const DAC_MUTE: u8 = 0x04;
+/// LOUT1/ROUT1/LOUT2/ROUT2 at 0dB
+const OUT_0DB: u8 = 0x1e;
+
+pub struct Es8388 {
+    bus: Bus,
+}
+
+impl Es8388 {
+    pub fn new(bus: Bus) -> Es8388 {
+        Es8388 { bus }
+    }
+}
+
+impl Dac for Es8388 {
+    fn init(&mut self, format: &OutputFormat, _rate: u32, mclk: Option<u16>) -> anyhow::Result<()> {
+        let Some(mclk) = mclk else {
+            anyhow::bail!("ES8388 needs MCLK, set mclk_pin");
+        };
+        // DACFsRatio
+        let ratio = match mclk {
+            128 => 0b00000,
+            256 => 0b00010,
+            384 => 0b00011,
+            _ => anyhow::bail!("ES8388 can't run off MCLK = {mclk}fs"),
+        };
+        let word_length = match format.slot_bits {
+            16 => 0b011,
+            24 => 0b000,
+            _ => 0b100,
+        };
+        let framing = match format.framing {
+            Framing::Philips => 0b00,
+            Framing::Msb => 0b01,
+            Framing::Pcm => 0b11,
+        };
+        let b = &mut self.bus;
+        b.write_reg(DACCONTROL3, DAC_RAMP | DAC_MUTE)?;
+        // reference on, DACs and outputs off until powered
+        b.write_reg(CONTROL2, 0x50)?;
+        b.write_reg(CHIPPOWER, 0x00)?;
+        b.write_reg(DACPOWER, 0xc0)?;
+        // slave, DAC and ADC share LRCK
+        b.write_reg(MASTERMODE, 0x00)?;
+        b.write_reg(DACCONTROL21, 0x80)?;
+        b.write_reg(CONTROL1, 0x12)?;
+        b.write_reg(DACCONTROL1, (word_length << 3) | (framing << 1))?;
+        // single speed
+        b.write_reg(DACCONTROL2, ratio)?;
+        // DACs to the output mixers only
+        b.write_reg(DACCONTROL17, 0x90)?;
+        b.write_reg(DACCONTROL20, 0x90)?;
+        for out in 0..4 {
+            b.write_reg(DACCONTROL24 + out, OUT_0DB)?;
+        }
+        Ok(())
+    }
+
+    fn set_attenuation(&mut self, half_db: Option<u32>) -> anyhow::Result<()> {
+        // -0.5dB steps, 0xc0 is -96dB
+        let att = half_db.map_or(0xc0, |att| att.min(0xc0)) as u8;
+        self.bus.write_reg(DACCONTROL4, att)?;
+        self.bus.write_reg(DACCONTROL5, att)
+    }
+
+    fn set_muted(&mut self, muted: bool) -> anyhow::Result<()> {
+        let mute = if muted { DAC_MUTE } else { 0 };
+        self.bus.write_reg(DACCONTROL3, DAC_RAMP | mute)
+    }
+
+    fn set_powered(&mut self, on: bool) -> anyhow::Result<()> {
+        // DACs and all four outputs
+        let power = if on { 0x3c } else { 0xc0 };
+        self.bus.write_reg(DACPOWER, power)
+    }
+}
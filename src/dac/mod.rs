@@ -0,0 +1,101 @@
+// Control of codec chips over I2C: the samples still go out over I2S, but
+// volume, mute and power are applied in the chip, where lowering the volume
+// does not throw away bits of the 16 bit samples.
+
+use anyhow::Context;
+use esp_idf_hal::delay::BLOCK;
+use esp_idf_hal::i2c::I2cDriver;
+
+use crate::output::OutputFormat;
+
+mod ac101;
+mod es8388;
+mod tas5805m;
+mod wm8960;
+
+pub trait Dac: Send {
+    /// Configures the chip to receive `format` at `rate`, powered down.
+    /// `mclk` is the MCLK frequency as a multiple of `rate`, None when MCLK is
+    /// not routed.
+    fn init(&mut self, format: &OutputFormat, rate: u32, mclk: Option<u16>) -> anyhow::Result<()>;
+    /// Below 0dB in 0.5dB steps, clamped to what the chip can do; None is
+    /// silent
+    fn set_attenuation(&mut self, half_db: Option<u32>) -> anyhow::Result<()>;
+    fn set_muted(&mut self, muted: bool) -> anyhow::Result<()>;
+    /// The I2S clocks are running whenever this is called, so that the chip
+    /// can sequence its outputs without popping
+    fn set_powered(&mut self, on: bool) -> anyhow::Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip {
+    Es8388,
+    Wm8960,
+    Tas5805m,
+    Ac101,
+}
+
+impl Chip {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Chip::Es8388 => "es8388",
+            Chip::Wm8960 => "wm8960",
+            Chip::Tas5805m => "tas5805m",
+            Chip::Ac101 => "ac101",
+        }
+    }
+
+    /// 7 bit I2C address with the address pins in their usual state
+    pub fn default_addr(&self) -> u8 {
+        match self {
+            Chip::Es8388 => 0x10,
+            Chip::Wm8960 => 0x1a,
+            // ADR pulled up with 15k
+            Chip::Tas5805m => 0x2d,
+            Chip::Ac101 => 0x1a,
+        }
+    }
+}
+
+impl std::str::FromStr for Chip {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Chip> {
+        Ok(match s {
+            "es8388" => Chip::Es8388,
+            "wm8960" => Chip::Wm8960,
+            "tas5805m" => Chip::Tas5805m,
+            "ac101" => Chip::Ac101,
+            _ => anyhow::bail!("DAC is es8388, wm8960, tas5805m or ac101"),
+        })
+    }
+}
+
+/// A chip's end of the I2C bus
+pub(crate) struct Bus {
+    i2c: I2cDriver<'static>,
+    addr: u8,
+}
+
+impl Bus {
+    fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        self.i2c
+            .write(self.addr, bytes, BLOCK)
+            .with_context(|| format!("I2C write {bytes:02x?} to 0x{:02x}", self.addr))
+    }
+
+    /// 8 bit register, 8 bit value
+    fn write_reg(&mut self, reg: u8, val: u8) -> anyhow::Result<()> {
+        self.write(&[reg, val])
+    }
+}
+
+pub fn open(chip: Chip, i2c: I2cDriver<'static>, addr: u8) -> Box<dyn Dac> {
+    let bus = Bus { i2c, addr };
+    match chip {
+        Chip::Es8388 => Box::new(es8388::Es8388::new(bus)),
+        Chip::Wm8960 => Box::new(wm8960::Wm8960::new(bus)),
+        Chip::Tas5805m => Box::new(tas5805m::Tas5805m::new(bus)),
+        Chip::Ac101 => Box::new(ac101::Ac101::new(bus)),
+    }
+}
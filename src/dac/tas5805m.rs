@@ -0,0 +1,101 @@
+// TI TAS5805M class D amplifier, BTL stereo. It derives its clocks from BCLK,
+// and has to see them before it leaves Hi-Z.
+// All registers used here are in book 0, page 0.
+
+use std::time::Duration;
+
+use crate::output::{Framing, OutputFormat};
+
+use super::{Bus, Dac};
+
+const PAGE: u8 = 0x00;
+const DEVICE_CTRL2: u8 = 0x03;
+const SAP_CTRL1: u8 = 0x33;
+const DIG_VOL: u8 = 0x4c;
+const BOOK: u8 = 0x7f;
+
+/// DEVICE_CTRL2 states
+const DEEP_SLEEP: u8 = 0x00;
+const HIZ: u8 = 0x02;
+const PLAY: u8 = 0x03;
+const MUTE: u8 = 0x08;
+
+/// DIG_VOL: 0dB, in -0.5dB steps up to 0xfe; 0xff is mute
+const VOL_0DB: u8 = 0x30;
+
+pub struct Tas5805m {
+    bus: Bus,
+    muted: bool,
+    powered: bool,
+}
+
+impl Tas5805m {
+    pub fn new(bus: Bus) -> Tas5805m {
+        Tas5805m {
+            bus,
+            muted: false,
+            powered: false,
+        }
+    }
+
+    fn update_state(&mut self) -> anyhow::Result<()> {
+        let state = match (self.powered, self.muted) {
+            (false, _) => DEEP_SLEEP,
+            (true, false) => PLAY,
+            (true, true) => PLAY | MUTE,
+        };
+        self.bus.write_reg(DEVICE_CTRL2, state)
+    }
+}
+
+impl Dac for Tas5805m {
+    fn init(
+        &mut self,
+        format: &OutputFormat,
+        _rate: u32,
+        _mclk: Option<u16>,
+    ) -> anyhow::Result<()> {
+        let word_length = match format.slot_bits {
+            16 => 0b00,
+            24 => 0b10,
+            _ => 0b11,
+        };
+        let framing = match format.framing {
+            Framing::Philips => 0b00,
+            Framing::Pcm => 0b01,
+            Framing::Msb => 0b11,
+        };
+        let b = &mut self.bus;
+        b.write_reg(PAGE, 0)?;
+        b.write_reg(BOOK, 0)?;
+        b.write_reg(PAGE, 0)?;
+        b.write_reg(DEVICE_CTRL2, DEEP_SLEEP)?;
+        b.write_reg(SAP_CTRL1, (framing << 4) | word_length)?;
+        self.powered = false;
+        Ok(())
+    }
+
+    fn set_attenuation(&mut self, half_db: Option<u32>) -> anyhow::Result<()> {
+        // 0xff is mute
+        let val = half_db.map_or(0xff, |att| {
+            VOL_0DB + att.min(u32::from(0xfe - VOL_0DB)) as u8
+        });
+        self.bus.write_reg(DIG_VOL, val)
+    }
+
+    fn set_muted(&mut self, muted: bool) -> anyhow::Result<()> {
+        self.muted = muted;
+        self.update_state()
+    }
+
+    fn set_powered(&mut self, on: bool) -> anyhow::Result<()> {
+        if on && !self.powered {
+            // the datasheet's start-up sequence: Hi-Z with clocks present,
+            // then play once the internal PLL locked
+            self.bus.write_reg(DEVICE_CTRL2, HIZ)?;
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        self.powered = on;
+        self.update_state()
+    }
+}
@@ -0,0 +1,104 @@
+// Cirrus/Wolfson WM8960: I2S slave with SYSCLK = MCLK, 256 or 384fs; the DACs feed
+// the headphone outputs and the class D speaker drivers.
+// Registers are 7 bit addresses with 9 bit values and can't be read back.
+
+use crate::output::{Framing, OutputFormat};
+
+use super::{Bus, Dac};
+
+const LOUT1_VOL: u16 = 0x02;
+const ROUT1_VOL: u16 = 0x03;
+const CLOCKING1: u16 = 0x04;
+const DAC_CTRL1: u16 = 0x05;
+const AUDIO_IFACE: u16 = 0x07;
+const LDAC_VOL: u16 = 0x0a;
+const RDAC_VOL: u16 = 0x0b;
+const RESET: u16 = 0x0f;
+const POWER1: u16 = 0x19;
+const POWER2: u16 = 0x1a;
+const LOUT_MIX: u16 = 0x22;
+const ROUT_MIX: u16 = 0x25;
+const LSPK_VOL: u16 = 0x28;
+const RSPK_VOL: u16 = 0x29;
+const CLASSD1: u16 = 0x31;
+const POWER3: u16 = 0x2f;
+
+/// Volume update bit: the left/right pair changes together on the right write
+const VU: u16 = 0x100;
+/// DAC_CTRL1: DACMU
+const DAC_MUTE: u16 = 0x008;
+/// POWER2: DACL, DACR, LOUT1, ROUT1, SPKL, SPKR
+const POWER2_OUTPUTS: u16 = 0x1f8;
+
+pub struct Wm8960 {
+    bus: Bus,
+}
+
+impl Wm8960 {
+    pub fn new(bus: Bus) -> Wm8960 {
+        Wm8960 { bus }
+    }
+
+    fn write(&mut self, reg: u16, val: u16) -> anyhow::Result<()> {
+        let word = (reg << 9) | (val & 0x1ff);
+        self.bus.write(&word.to_be_bytes())
+    }
+}
+
+impl Dac for Wm8960 {
+    fn init(&mut self, format: &OutputFormat, _rate: u32, mclk: Option<u16>) -> anyhow::Result<()> {
+        let Some(mclk) = mclk else {
+            anyhow::bail!("WM8960 needs MCLK, set mclk_pin");
+        };
+        // DACDIV: the DAC runs at SYSCLK / (DACDIV * 256)
+        let dac_div = match mclk {
+            256 => 0b000,
+            384 => 0b001,
+            _ => anyhow::bail!("WM8960 can't run off MCLK = {mclk}fs"),
+        };
+        let word_length = match format.slot_bits {
+            16 => 0b00,
+            24 => 0b10,
+            _ => 0b11,
+        };
+        let framing = match format.framing {
+            Framing::Philips => 0b10,
+            Framing::Msb => 0b01,
+            Framing::Pcm => 0b11,
+        };
+        self.write(RESET, 0)?;
+        // VMID 2x50k divider and VREF
+        self.write(POWER1, 0x0c0)?;
+        self.write(POWER2, 0)?;
+        // left and right output mixers
+        self.write(POWER3, 0x00c)?;
+        self.write(CLOCKING1, dac_div << 3)?;
+        self.write(AUDIO_IFACE, (word_length << 2) | framing)?;
+        self.write(DAC_CTRL1, DAC_MUTE)?;
+        self.write(LOUT_MIX, 0x100)?;
+        self.write(ROUT_MIX, 0x100)?;
+        // both class D channels
+        self.write(CLASSD1, 0x0f7)?;
+        // analog outputs at 0dB, zero cross
+        self.write(LOUT1_VOL, 0x0f9)?;
+        self.write(ROUT1_VOL, VU | 0x0f9)?;
+        self.write(LSPK_VOL, 0x0f9)?;
+        self.write(RSPK_VOL, VU | 0x0f9)
+    }
+
+    fn set_attenuation(&mut self, half_db: Option<u32>) -> anyhow::Result<()> {
+        // 0xff is 0dB in -0.5dB steps, 0 is digital mute
+        let val = half_db.map_or(0, |att| 0xff - att.min(0xfe) as u16);
+        self.write(LDAC_VOL, val)?;
+        self.write(RDAC_VOL, VU | val)
+    }
+
+    fn set_muted(&mut self, muted: bool) -> anyhow::Result<()> {
+        self.write(DAC_CTRL1, if muted { DAC_MUTE } else { 0 })
+    }
+
+    fn set_powered(&mut self, on: bool) -> anyhow::Result<()> {
+        // VMID stays up: charging it again is what pops
+        self.write(POWER2, if on { POWER2_OUTPUTS } else { 0 })
+    }
+}
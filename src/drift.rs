@@ -0,0 +1,151 @@
+// Smooth clock drift correction.
+//
+// Once playback is locked to the server's timeline, every chunk reports how far
+// off its start is (the scheduling error). A single measurement is noisy: the
+// I2S write returns whenever a DMA buffer frees up, so it jitters by up to one
+// buffer (~10ms). The error is low-pass filtered, and the slow residual (crystal
+// drift, SNTP slewing) is corrected by inserting or dropping single frames,
+// spread over the chunk, instead of sleeping or skipping whole milliseconds.
+
+/// Errors larger than this are not drift; the caller re-syncs with silence
+/// padding or a skip. Must exceed the per-chunk jitter of one DMA buffer.
+pub const HARD_SYNC_US: i64 = 15_000;
+
+/// Upper bound for frames inserted per chunk: the decode buffer needs this much
+/// headroom (times the channel count)
+pub const MAX_FRAMES_PER_CHUNK: usize = 2;
+
+/// EMA weight of a new measurement, 1/64: ~1.3s time constant at 20ms chunks
+const FILTER_SHIFT: u32 = 6;
+/// Fixed-point fraction bits of the filtered error
+const FRAC_BITS: u32 = 8;
+/// Measurements to average before correcting anything
+const WARMUP_CHUNKS: u16 = 64;
+/// Residual error that is left alone, well below audible offsets
+const DEADBAND_US: i64 = 250;
+
+pub struct DriftCorrector {
+    locked: bool,
+    warmup: u16,
+    /// Filtered scheduling error, microseconds << FRAC_BITS;
+    /// positive = playing early
+    filtered: i64,
+}
+
+impl Default for DriftCorrector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DriftCorrector {
+    pub fn new() -> DriftCorrector {
+        DriftCorrector {
+            locked: false,
+            warmup: WARMUP_CHUNKS,
+            filtered: 0,
+        }
+    }
+
+    /// Whether the previous chunks were hard-synced and played back to back
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Called after a hard sync: the next chunk is contiguous with this one
+    pub fn lock(&mut self) {
+        self.locked = true;
+        self.warmup = WARMUP_CHUNKS;
+        self.filtered = 0;
+    }
+
+    /// Called on any discontinuity: a dropped chunk, an underrun, a hard sync
+    pub fn reset(&mut self) {
+        self.locked = false;
+    }
+
+    pub fn filtered_us(&self) -> i64 {
+        self.filtered >> FRAC_BITS
+    }
+
+    /// Feeds the scheduling error of a locked chunk (positive: the chunk would
+    /// play too early) and corrects `buf[..len]`, interleaved with `channels`.
+    /// `buf` needs `MAX_FRAMES_PER_CHUNK * channels` samples of headroom past
+    /// `len`. Returns the new length.
+    pub fn correct(
+        &mut self,
+        error_us: i64,
+        rate: u32,
+        buf: &mut [i16],
+        len: usize,
+        channels: usize,
+    ) -> usize {
+        let sample = error_us << FRAC_BITS;
+        self.filtered += (sample - self.filtered) >> FILTER_SHIFT;
+        if self.warmup > 0 {
+            self.warmup -= 1;
+            return len;
+        }
+
+        let err = self.filtered_us();
+        if err.abs() < DEADBAND_US {
+            return len;
+        }
+        // Frames owed, rounded down; corrections are spread over several chunks
+        let frames = len / channels;
+        let owed = (err.unsigned_abs() * u64::from(rate) / 1_000_000) as usize;
+        let count = owed.clamp(1, MAX_FRAMES_PER_CHUNK).min(frames / 4);
+        if count == 0 {
+            return len;
+        }
+
+        // Account for the correction immediately: it only shows up in the
+        // measurements once it went through the DMA ring
+        let step = (count as i64 * 1_000_000 / i64::from(rate)) << FRAC_BITS;
+        if err > 0 {
+            self.filtered -= step;
+            insert_frames(buf, len, channels, count)
+        } else {
+            self.filtered += step;
+            drop_frames(buf, len, channels, count)
+        }
+    }
+}
+
+/// Inserts `count` frames evenly spread over `buf[..len]`, each the average of
+/// its neighbours. Returns the new length.
+fn insert_frames(buf: &mut [i16], len: usize, channels: usize, count: usize) -> usize {
+    let frames = len / channels;
+    let mut len = len;
+    // back to front, so that earlier positions stay valid
+    for i in (1..=count).rev() {
+        let at = (frames * i / (count + 1)) * channels;
+        buf.copy_within(at..len, at + channels);
+        for c in 0..channels {
+            // the frame at `at` moved up; its old slot averages both neighbours
+            let prev = buf[at - channels + c] as i32;
+            let next = buf[at + channels + c] as i32;
+            buf[at + c] = ((prev + next) / 2) as i16;
+        }
+        len += channels;
+    }
+    len
+}
+
+/// Merges `count` pairs of adjacent frames, evenly spread over `buf[..len]`,
+/// into their average. Returns the new length.
+fn drop_frames(buf: &mut [i16], len: usize, channels: usize, count: usize) -> usize {
+    let frames = len / channels;
+    let mut len = len;
+    for i in (1..=count).rev() {
+        let at = (frames * i / (count + 1)) * channels;
+        for c in 0..channels {
+            let a = buf[at + c] as i32;
+            let b = buf[at + channels + c] as i32;
+            buf[at + c] = ((a + b) / 2) as i16;
+        }
+        buf.copy_within(at + 2 * channels..len, at + channels);
+        len -= channels;
+    }
+    len
+}
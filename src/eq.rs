@@ -0,0 +1,266 @@
+// A per-device equalizer: small speakers want their bass cut before it
+// distorts, and rooms or grilles add peaks of their own. The bands are biquads
+// from the RBJ cookbook, run in fixed point.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+use std::f64::consts::PI;
+use std::fmt;
+
+pub const MAX_BANDS: usize = 6;
+
+/// Coefficients are Q28 in an i32: up to +-8. The largest is a +12dB high
+/// shelf's b1, just under 2 * 10^(12/20) = 7.96
+const COEF_BITS: u32 = 28;
+/// Samples carry this many extra fractional bits from band to band, so that
+/// the rounding noise of a chain stays below the 16 bit floor
+const EXTRA_BITS: u32 = 8;
+
+/// Butterworth: flat up to the corner
+const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Peak,
+    LowShelf,
+    HighShelf,
+    HighPass,
+    LowPass,
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Peak => "peak",
+            Kind::LowShelf => "lowshelf",
+            Kind::HighShelf => "highshelf",
+            Kind::HighPass => "highpass",
+            Kind::LowPass => "lowpass",
+        }
+    }
+
+    fn has_gain(&self) -> bool {
+        !matches!(self, Kind::HighPass | Kind::LowPass)
+    }
+}
+
+impl std::str::FromStr for Kind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Kind> {
+        Ok(match s {
+            "peak" => Kind::Peak,
+            "lowshelf" => Kind::LowShelf,
+            "highshelf" => Kind::HighShelf,
+            "highpass" => Kind::HighPass,
+            "lowpass" => Kind::LowPass,
+            _ => anyhow::bail!("EQ band is peak, lowshelf, highshelf, highpass or lowpass"),
+        })
+    }
+}
+
+/// One filter, written `kind:freq:gain_db:q`. Pass filters have no gain, and
+/// Q may be left out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub kind: Kind,
+    pub freq: f32,
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl std::str::FromStr for Band {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Band> {
+        let fields: Vec<&str> = s.split(':').collect();
+        let kind: Kind = fields[0].parse()?;
+        let (freq, gain, q) = match (kind.has_gain(), &fields[1..]) {
+            (true, [freq, gain]) => (freq, *gain, None),
+            (true, [freq, gain, q]) => (freq, *gain, Some(q)),
+            (false, [freq]) => (freq, "0", None),
+            (false, [freq, q]) => (freq, "0", Some(q)),
+            _ => anyhow::bail!("EQ band '{s}' is not kind:freq:gain:q"),
+        };
+        let band = Band {
+            kind,
+            freq: freq.parse()?,
+            gain_db: gain.parse()?,
+            q: q.map_or(Ok(DEFAULT_Q), |q| q.parse())?,
+        };
+        anyhow::ensure!(
+            (20.0..=20_000.0).contains(&band.freq),
+            "EQ frequency is 20-20000Hz"
+        );
+        anyhow::ensure!(
+            (-24.0..=12.0).contains(&band.gain_db),
+            "EQ gain is -24 to +12dB"
+        );
+        anyhow::ensure!((0.1..=10.0).contains(&band.q), "EQ Q is 0.1-10");
+        Ok(band)
+    }
+}
+
+impl fmt::Display for Band {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.kind.as_str(), self.freq)?;
+        if self.kind.has_gain() {
+            write!(f, ":{}", self.gain_db)?;
+        }
+        write!(f, ":{}", self.q)
+    }
+}
+
+/// Comma separated bands, e.g. `highpass:80,peak:3000:-4:1.4`
+pub fn parse_bands(s: &str) -> anyhow::Result<Vec<Band>> {
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+    let bands = s
+        .split(',')
+        .map(str::parse)
+        .collect::<anyhow::Result<Vec<Band>>>()?;
+    anyhow::ensure!(bands.len() <= MAX_BANDS, "at most {MAX_BANDS} EQ bands");
+    Ok(bands)
+}
+
+pub fn format_bands(bands: &[Band]) -> String {
+    bands
+        .iter()
+        .map(Band::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+impl Band {
+    /// Normalized to a0 = 1: b0, b1, b2, a1, a2
+    fn coefficients(&self, rate: u32) -> [f64; 5] {
+        let rate = f64::from(rate);
+        // a corner at or above Nyquist has no filter
+        let freq = f64::from(self.freq).min(rate * 0.45);
+        let w0 = 2.0 * PI * freq / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * f64::from(self.q));
+        let a = 10f64.powf(f64::from(self.gain_db) / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        let [b0, b1, b2, a0, a1, a2] = match self.kind {
+            Kind::Peak => [
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ],
+            Kind::LowShelf => [
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ],
+            Kind::HighShelf => [
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ],
+            Kind::HighPass => [
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ],
+            Kind::LowPass => [
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ],
+        };
+        [b0 / a0, b1 / a0, b2 / a0, a1 / a0, a2 / a0]
+    }
+}
+
+/// Direct form I: the state is the last two inputs and outputs, per channel
+struct Biquad {
+    coef: [i32; 5],
+    state: Vec<[i32; 4]>,
+}
+
+impl Biquad {
+    fn new(band: &Band, rate: u32, channels: usize) -> Biquad {
+        let coef = band
+            .coefficients(rate)
+            .map(|c| (c * f64::from(1u32 << COEF_BITS)).round() as i32);
+        Biquad {
+            coef,
+            state: vec![[0; 4]; channels],
+        }
+    }
+
+    fn run(&mut self, x: i32, channel: usize) -> i32 {
+        let [b0, b1, b2, a1, a2] = self.coef;
+        let [x1, x2, y1, y2] = self.state[channel];
+        let acc = i64::from(b0) * i64::from(x)
+            + i64::from(b1) * i64::from(x1)
+            + i64::from(b2) * i64::from(x2)
+            - i64::from(a1) * i64::from(y1)
+            - i64::from(a2) * i64::from(y2);
+        let y = ((acc + (1 << (COEF_BITS - 1))) >> COEF_BITS) as i32;
+        self.state[channel] = [x, x1, y, y1];
+        y
+    }
+}
+
+pub struct Equalizer {
+    bands: Vec<Band>,
+    channels: usize,
+    /// The filters are built for this rate
+    rate: u32,
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(bands: Vec<Band>, channels: usize) -> Equalizer {
+        Equalizer {
+            bands,
+            channels,
+            rate: 0,
+            filters: Vec::new(),
+        }
+    }
+
+    /// Filters `buf`, which holds frames of interleaved samples at `rate`
+    pub fn process(&mut self, buf: &mut [i16], rate: u32) {
+        if self.bands.is_empty() {
+            return;
+        }
+        if rate != self.rate {
+            self.rate = rate;
+            self.filters = self
+                .bands
+                .iter()
+                .map(|b| Biquad::new(b, rate, self.channels))
+                .collect();
+        }
+        for frame in buf.chunks_exact_mut(self.channels) {
+            for (channel, s) in frame.iter_mut().enumerate() {
+                let mut x = i32::from(*s) << EXTRA_BITS;
+                for f in &mut self.filters {
+                    x = f.run(x, channel);
+                }
+                let y = (x + (1 << (EXTRA_BITS - 1))) >> EXTRA_BITS;
+                // boosts clip; the bands should cut more than they boost
+                *s = y.clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16;
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use snapcast_client::playback::Player;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::output::Channel;
+use crate::player::I2sPlayer;
+use crate::{ota, status, util};
+
+type SharedPlayer = Arc<Mutex<Option<I2sPlayer>>>;
+
+/// Reads a short `text/plain` body, e.g. `curl -d 40 http://<esp>/volume`
+fn read_body<'a>(
+    req: &mut Request<&mut EspHttpConnection>,
+    buf: &'a mut [u8],
+) -> anyhow::Result<&'a str> {
+    let mut len = 0;
+    while len < buf.len() {
+        match req.read(&mut buf[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    Ok(std::str::from_utf8(&buf[..len])?.trim())
+}
+
+fn parse_bool(s: &str) -> anyhow::Result<bool> {
+    match s {
+        "1" | "true" | "on" => Ok(true),
+        "0" | "false" | "off" => Ok(false),
+        _ => anyhow::bail!("expected 0 or 1"),
+    }
+}
+
+/// Runs `f` on the player, answering 400 on a bad request and 503 while no
+/// stream has initialized the player yet
+fn with_player<T>(
+    req: Request<&mut EspHttpConnection>,
+    player: &SharedPlayer,
+    arg: anyhow::Result<T>,
+    f: impl FnOnce(&mut I2sPlayer, T) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let arg = match arg {
+        Ok(a) => a,
+        Err(e) => {
+            req.into_status_response(400)?
+                .write_all(format!("{e}\n").as_bytes())?;
+            return Ok(());
+        }
+    };
+    match player.lock().unwrap().as_mut() {
+        Some(p) => {
+            f(p, arg)?;
+            req.into_ok_response()?.write_all(b"OK\n")?;
+        }
+        None => {
+            req.into_status_response(503)?
+                .write_all(b"player not initialized\n")?;
+        }
+    }
+    Ok(())
+}
+
+/// LAN-facing HTTP server:
+/// - `GET /status`: JSON status
+/// - `POST /volume` (0-100), `POST /mute` (0/1)
+/// - `POST /channel` (stereo/left/right/mix/swapped), until reboot
+/// - `POST /reconnect`, `POST /reboot`
+/// - `POST /ota`, see `ota`
+pub fn start(player: SharedPlayer) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&Configuration {
+        // the listen socket + one connection, see sdkconfig.defaults
+        max_open_sockets: 1,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/status", Method::Get, |req| -> anyhow::Result<()> {
+        req.into_response(200, None, &[("Content-Type", "application/json")])?
+            .write_all(status::to_json().as_bytes())?;
+        Ok(())
+    })?;
+
+    let player_2 = player.clone();
+    server.fn_handler(
+        "/volume",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let mut buf = [0u8; 8];
+            let vol = read_body(&mut req, &mut buf).and_then(|b| {
+                let vol: u8 = b.parse()?;
+                anyhow::ensure!(vol <= 100, "volume is 0-100");
+                Ok(vol)
+            });
+            with_player(req, &player_2, vol, |p, vol| p.set_volume(vol))
+        },
+    )?;
+
+    let player_3 = player.clone();
+    server.fn_handler(
+        "/channel",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let mut buf = [0u8; 8];
+            let channel = read_body(&mut req, &mut buf).and_then(|b| b.parse::<Channel>());
+            with_player(req, &player_3, channel, |p, channel| {
+                p.set_channel(channel);
+                Ok(())
+            })
+        },
+    )?;
+
+    server.fn_handler(
+        "/mute",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let mut buf = [0u8; 8];
+            let muted = read_body(&mut req, &mut buf).and_then(parse_bool);
+            with_player(req, &player, muted, |p, muted| {
+                p.set_muted(muted);
+                Ok(())
+            })
+        },
+    )?;
+
+    server.fn_handler("/reconnect", Method::Post, |req| -> anyhow::Result<()> {
+        status::request_reconnect();
+        req.into_ok_response()?.write_all(b"OK\n")?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/reboot", Method::Post, |req| -> anyhow::Result<()> {
+        req.into_ok_response()?.write_all(b"OK, rebooting\n")?;
+        util::restart_after(Duration::from_secs(1));
+        Ok(())
+    })?;
+
+    ota::register(&mut server)?;
+    Ok(server)
+}
@@ -0,0 +1,120 @@
+// What the output does between chunks. Nothing is made up to fill a gap: the
+// DMA plays silence, and the debug tone only plays when it was asked for.
+
+use std::time::{Duration, Instant};
+
+use crate::status;
+
+/// Without a chunk for this long the DMA ring has run dry
+const UNDERRUN_AFTER: Duration = Duration::from_millis(500);
+const IDLE_AFTER: Duration = Duration::from_secs(5);
+const TONE_EVERY: Duration = Duration::from_secs(5);
+/// How long the consumer waits for a chunk before checking on the output
+pub const POLL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// Chunks are being played
+    Streaming,
+    /// The stream stalled; the output plays silence
+    Underrun,
+    /// Nothing played for a while
+    Idle,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Streaming => "streaming",
+            State::Underrun => "underrun",
+            State::Idle => "idle",
+        }
+    }
+}
+
+/// What to write while no chunks arrive
+pub enum Action {
+    None,
+    /// Silence over whatever the DMA ring still holds
+    Flush,
+    Tone,
+}
+
+pub struct IdleMonitor {
+    state: State,
+    last_chunk: Instant,
+    last_tone: Instant,
+    /// The DMA repeats its buffers on an underrun instead of sending zeroes
+    flush: bool,
+    tone: bool,
+}
+
+impl IdleMonitor {
+    pub fn new(flush: bool, tone: bool) -> IdleMonitor {
+        let mut monitor = IdleMonitor {
+            state: State::Idle,
+            last_chunk: Instant::now(),
+            last_tone: Instant::now(),
+            flush,
+            tone,
+        };
+        monitor.set(State::Idle);
+        monitor
+    }
+
+    fn set(&mut self, state: State) {
+        if state != self.state {
+            log::info!("output {} -> {}", self.state.as_str(), state.as_str());
+        }
+        self.state = state;
+        *status::OUTPUT.lock().unwrap() = state.as_str();
+    }
+
+    /// A chunk arrived. True if it ends an underrun or idle period, so that it
+    /// is not contiguous with what played before.
+    pub fn on_chunk(&mut self) -> bool {
+        self.last_chunk = Instant::now();
+        let resumed = self.state != State::Streaming;
+        self.set(State::Streaming);
+        resumed
+    }
+
+    /// Called whenever waiting for a chunk timed out
+    pub fn poll(&mut self) -> Action {
+        let quiet = self.last_chunk.elapsed();
+        match self.state {
+            State::Streaming if quiet >= UNDERRUN_AFTER => {
+                self.set(State::Underrun);
+                if self.flush {
+                    Action::Flush
+                } else {
+                    Action::None
+                }
+            }
+            State::Underrun if quiet >= IDLE_AFTER => {
+                self.set(State::Idle);
+                self.last_tone = Instant::now();
+                Action::None
+            }
+            State::Idle if self.tone && self.last_tone.elapsed() >= TONE_EVERY => {
+                self.last_tone = Instant::now();
+                Action::Tone
+            }
+            _ => Action::None,
+        }
+    }
+}
+
+/// Fills `buf` with a quiet triangle wave, peaking at 1024 (-30dBFS) every
+/// 256 samples
+pub fn tone(buf: &mut [i16]) {
+    let mut inc = -1;
+    let mut ampl: i16 = 0;
+    for (i, item) in buf.iter_mut().enumerate() {
+        if (i % 128) == 0 {
+            inc = -inc;
+        }
+        ampl += inc;
+        *item = ampl * 8;
+    }
+}
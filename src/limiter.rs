@@ -0,0 +1,111 @@
+// A peak limiter for small drivers that distort when pushed: the audio is
+// delayed by a few ms, so that the gain is already down when a peak reaches
+// the output, instead of clipping it.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+use crate::ramp::UNITY;
+
+pub const LOOKAHEAD_MS: u32 = 2;
+
+pub struct Limiter {
+    channels: usize,
+    /// Peaks are brought down to this
+    threshold: i32,
+    /// Per frame, Q15
+    release_step: i32,
+    /// `lookahead` frames of interleaved samples, oldest at `pos`
+    delay: Vec<i16>,
+    pos: usize,
+    lookahead: usize,
+    gain: i32,
+    attack_step: i32,
+    /// Lowest gain required by the frames in the delay line, and how many
+    /// frames it still applies for
+    held: i32,
+    hold: usize,
+}
+
+impl Limiter {
+    /// `threshold_db` is at or below 0dBFS; the gain recovers from 0 to unity
+    /// over `release_ms`
+    pub fn new(threshold_db: f32, release_ms: u32, rate: u32, channels: usize) -> Limiter {
+        let lookahead = (LOOKAHEAD_MS * rate / 1000).max(1) as usize;
+        let release_frames = (release_ms * rate / 1000).max(1);
+        let threshold = f32::from(i16::MAX) * 10f32.powf(threshold_db.min(0.0) / 20.0);
+        Limiter {
+            channels,
+            threshold: threshold as i32,
+            release_step: (UNITY as u32).div_ceil(release_frames) as i32,
+            delay: vec![0; lookahead * channels],
+            pos: 0,
+            lookahead,
+            gain: UNITY,
+            attack_step: 0,
+            held: UNITY,
+            hold: 0,
+        }
+    }
+
+    /// Frames are delayed by this many frames
+    pub fn lookahead(&self) -> usize {
+        self.lookahead
+    }
+
+    /// Drops what is still in the delay line, e.g. after the output ran dry
+    pub fn reset(&mut self) {
+        self.delay.fill(0);
+        self.gain = UNITY;
+        self.attack_step = 0;
+        self.held = UNITY;
+        self.hold = 0;
+    }
+
+    /// Gain that brings a peak down to the threshold
+    fn required(&self, peak: i32) -> i32 {
+        if peak <= self.threshold {
+            UNITY
+        } else {
+            ((i64::from(self.threshold) << 15) / i64::from(peak)) as i32
+        }
+    }
+
+    /// Limits `buf`, which holds frames of interleaved samples, in place
+    pub fn process(&mut self, buf: &mut [i16]) {
+        let ch = self.channels;
+        for frame in buf.chunks_exact_mut(ch) {
+            // the channels share a gain, so that the image does not move
+            let peak = frame.iter().map(|s| i32::from(*s).abs()).max().unwrap_or(0);
+            let required = self.required(peak);
+            if required <= self.held {
+                // this frame leaves the delay line in `lookahead` frames: get
+                // there by then
+                let step =
+                    (self.gain - required + self.lookahead as i32 - 1) / self.lookahead as i32;
+                self.attack_step = self.attack_step.max(step);
+                self.held = required;
+                self.hold = self.lookahead;
+            } else if self.hold > 0 {
+                self.hold -= 1;
+            } else {
+                self.held = required;
+            }
+
+            if self.gain > self.held {
+                self.gain = (self.gain - self.attack_step).max(self.held);
+            } else {
+                self.attack_step = 0;
+                self.gain = (self.gain + self.release_step).min(self.held);
+            }
+
+            let delayed = &mut self.delay[self.pos * ch..(self.pos + 1) * ch];
+            for (s, d) in frame.iter_mut().zip(delayed) {
+                let out = (i32::from(*d) * self.gain) >> 15;
+                *d = *s;
+                // a peak the hold let through is clipped
+                *s = out.clamp(-self.threshold, self.threshold) as i16;
+            }
+            self.pos = (self.pos + 1) % self.lookahead;
+        }
+    }
+}
@@ -0,0 +1,80 @@
+// Health of the Wi-Fi link, for diagnostics: why the AP dropped us (e.g.
+// reason 16, a group key handshake timeout during the AP's GTK rekey) and how
+// the signal looked before it did.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+use std::collections::VecDeque;
+
+/// RSSI samples kept, one per `wifi::RSSI_PERIOD`
+pub const RSSI_SAMPLES: usize = 30;
+
+#[derive(Default)]
+pub struct Link {
+    up: bool,
+    /// Times an established link went down
+    drops: u32,
+    last_reason: Option<u16>,
+    /// Disconnect reason codes and how often each was seen, by code
+    reasons: Vec<(u16, u32)>,
+    /// Oldest first
+    rssi: VecDeque<i8>,
+}
+
+impl Link {
+    pub const fn new() -> Link {
+        Link {
+            up: false,
+            drops: 0,
+            last_reason: None,
+            reasons: Vec::new(),
+            rssi: VecDeque::new(),
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.up
+    }
+
+    pub fn connected(&mut self) {
+        self.up = true;
+    }
+
+    /// Also called for every failed association, which is counted by reason
+    /// but is not a drop
+    pub fn disconnected(&mut self, reason: u16) {
+        if self.up {
+            self.drops += 1;
+        }
+        self.up = false;
+        self.last_reason = Some(reason);
+        match self.reasons.binary_search_by_key(&reason, |(r, _)| *r) {
+            Ok(i) => self.reasons[i].1 += 1,
+            Err(i) => self.reasons.insert(i, (reason, 1)),
+        }
+    }
+
+    pub fn sample_rssi(&mut self, rssi: i8) {
+        if self.rssi.len() == RSSI_SAMPLES {
+            self.rssi.pop_front();
+        }
+        self.rssi.push_back(rssi);
+    }
+
+    pub fn to_json(&self) -> String {
+        let reasons: Vec<String> = self
+            .reasons
+            .iter()
+            .map(|(r, n)| format!(r#""{r}":{n}"#))
+            .collect();
+        let rssi: Vec<String> = self.rssi.iter().map(i8::to_string).collect();
+        format!(
+            r#"{{"up":{},"drops":{},"last_reason":{},"reasons":{{{}}},"rssi_history":[{}]}}"#,
+            self.up,
+            self.drops,
+            self.last_reason.map_or("null".into(), |r| r.to_string()),
+            reasons.join(","),
+            rssi.join(","),
+        )
+    }
+}
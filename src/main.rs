@@ -1,11 +1,8 @@
-use anyhow::Context;
 use snapcast_client::client::{Client, ConnectedClient, Message};
 use snapcast_client::decoder::{Decode, Decoder};
-use snapcast_client::opus_embedded;
 use snapcast_client::playback::Player;
-use snapcast_client::proto::{CodecMetadata, TimeVal};
 
-use esp_idf_hal::gpio::{Gpio18, Gpio19, Gpio21};
+use esp_idf_hal::i2c::I2C0;
 use esp_idf_hal::i2s::I2S0;
 use esp_idf_hal::modem::Modem;
 use esp_idf_hal::peripherals::Peripherals;
@@ -13,16 +10,45 @@ use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
 use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::sys::*;
 
-use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
-use std::sync::{mpsc, mpsc::SyncSender, Arc, Condvar, Mutex};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicI32, AtomicU32, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 use std::time::{Duration, Instant};
 
+mod amp;
+mod codec;
+mod config;
 mod cpu;
+mod dac;
+mod drift;
+mod eq;
+mod http;
+mod idle;
+mod limiter;
+mod link;
+mod mdns;
+mod ota;
+mod output;
 mod player;
+mod provision;
+mod ramp;
+mod reconnect;
+mod resample;
+mod ringbuf;
+mod sched;
+mod status;
 mod util;
+mod volume;
 mod wifi;
 
+use config::Config;
+use eq::Equalizer;
+use idle::{Action, IdleMonitor};
 use player::{I2sPlayer, I2sPlayerBuilder};
+use reconnect::{Failure, Supervisor};
+use resample::Resampler;
+use ringbuf::{ChunkRing, Consumer, Popped, Producer};
+use sched::{Decision, Outcome, Scheduler, SystemClock};
 
 // JJJJJJJJJJJJJJJJJJJJJJJJJJJJJJJJ
 const SSID: [u8; 32] = [
@@ -37,60 +63,113 @@ const PASS: [u8; 64] = [
     0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x4b, 0x00,
 ];
 
-enum Sample {
-    Data(Vec<u8>),
-    WhiteNoise,
+// Must hold the full server buffer: the consumer blocks while writing the
+// silence ahead of an early chunk, so everything else queues here.
+// 80KiB = ~2.5s of opus (~250Kbit/s), but only ~650ms of FLAC (~1Mbit/s);
+// do not use large server buffers with FLAC.
+const RING_BYTES: usize = 80 * 1024;
+// FLAC chunks are 4-5KiB, up to 9KiB; PCM is 3840B per 20ms @48k stereo
+const MAX_CHUNK_BYTES: usize = 10 * 1024;
+const _: () = assert!(codec::MAX_ENCODED_CHUNK <= MAX_CHUNK_BYTES);
+const _: () = assert!(RING_BYTES >= 2 * (ringbuf::HEADER_LEN + MAX_CHUNK_BYTES));
+
+/// Takes the lock for each call only: an early chunk is preceded by up to
+/// `sched::MAX_AHEAD_SEC` of silence, and `connection_main` and the HTTP
+/// handlers must not wait for all of it
+struct SharedPlayer<'a, P>(&'a Mutex<Option<P>>);
+
+impl<P: Player> SharedPlayer<'_, P> {
+    fn with<T>(&self, f: impl FnOnce(&mut P) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        match self.0.lock().unwrap().as_mut() {
+            Some(p) => f(p),
+            None => anyhow::bail!("no player"),
+        }
+    }
 }
 
-// Storage for the ~26.6KiB opus decoder state: living in .bss means it is
-// accounted at link time, cannot fail to allocate and cannot fragment the heap.
-// HANDED_OUT pairs with the Decoder's lifetime: claim on Decoder::new, release
-// only after the borrowing Decoder has been dropped.
-struct OpusSlot(core::cell::UnsafeCell<core::mem::MaybeUninit<opus_embedded::Decoder>>);
-// SAFETY: HANDED_OUT serializes access
-unsafe impl Sync for OpusSlot {}
-
-static OPUS_SLOT: OpusSlot =
-    OpusSlot(core::cell::UnsafeCell::new(core::mem::MaybeUninit::uninit()));
-static OPUS_SLOT_HANDED_OUT: AtomicBool = AtomicBool::new(false);
-
-fn claim_opus_slot() -> &'static mut core::mem::MaybeUninit<opus_embedded::Decoder> {
-    let was_handed_out = OPUS_SLOT_HANDED_OUT.swap(true, Ordering::AcqRel);
-    assert!(!was_handed_out, "opus decoder slot is still borrowed");
-    // SAFETY: HANDED_OUT guarantees this is the only live borrow
-    unsafe { &mut *OPUS_SLOT.0.get() }
-}
+impl<P: Player> Player for SharedPlayer<'_, P> {
+    fn play(&mut self) -> anyhow::Result<()> {
+        self.with(|p| p.play())
+    }
+
+    fn write(&mut self, buf: &mut [i16]) -> anyhow::Result<()> {
+        self.with(|p| p.write(buf))
+    }
 
-fn release_opus_slot() {
-    OPUS_SLOT_HANDED_OUT.store(false, Ordering::Release);
+    fn latency_ms(&self) -> anyhow::Result<u16> {
+        self.with(|p| p.latency_ms())
+    }
+
+    fn set_volume(&mut self, val: u8) -> anyhow::Result<()> {
+        self.with(|p| p.set_volume(val))
+    }
+
+    fn sample_rate(&self) -> u16 {
+        self.with(|p| Ok(p.sample_rate())).unwrap_or(0)
+    }
 }
 
-fn handle_samples<P: Player>(
+#[allow(clippy::too_many_arguments)] // everything the decoder thread owns
+fn handle_samples(
     dec_sample_buf: &mut [i16],
-    sample_rx: mpsc::Receiver<(TimeVal, Sample)>,
+    resample_buf: &mut Vec<i16>,
+    enc_buf: &mut [u8],
+    mut consumer: Consumer,
     time_base_c: Instant,
-    player: Arc<Mutex<Option<P>>>,
+    player: Arc<Mutex<Option<I2sPlayer>>>,
     dec: Arc<Mutex<Option<Decoder>>>,
-    sample_count: Arc<AtomicU16>,
+    latency_ms: Arc<AtomicI32>,
+    source_rate: Arc<AtomicU32>,
+    mut idle: IdleMonitor,
+    mut eq: Equalizer,
 ) {
-    let mut samples_per_ms: u16 = 1; // irrelevant, will be overwritten
+    let mut sched = Scheduler::new(codec::CHANNELS);
+    let mut resampler = Resampler::new(codec::CHANNELS);
+    let clock = SystemClock::new(time_base_c);
 
     let mut free_heap = unsafe { esp_get_free_heap_size() };
     let mut window_min = u16::MAX;
     let mut last_status = Instant::now();
 
-    while let Ok((client_audible_ts, samples)) = sample_rx.recv() {
-        sample_count.fetch_sub(1, Ordering::AcqRel);
-        let in_buffer = sample_count.load(Ordering::Relaxed);
+    loop {
+        let (client_audible_ts, encoded) = match consumer.pop(enc_buf, idle::POLL) {
+            Popped::Chunk(ts, encoded) => (ts, encoded),
+            Popped::Timeout => {
+                let action = idle.poll();
+                let Some(ref mut p) = *player.lock().unwrap() else {
+                    continue;
+                };
+                match action {
+                    Action::None => {}
+                    Action::Flush => p.flush(),
+                    Action::Tone => {
+                        log::info!("debug tone");
+                        idle::tone(dec_sample_buf);
+                        p.write_tone(&mut dec_sample_buf[..codec::DEC_SAMPLES]);
+                    }
+                }
+                continue;
+            }
+            Popped::Closed => break,
+        };
+        if idle.on_chunk() {
+            // re-synced from scratch, the player primes its drained output first
+            log::info!("resuming playback");
+            sched.interrupt();
+            resampler.reset();
+        }
+        let in_buffer = consumer.fill_ms();
+        let bytes = consumer.fill_bytes();
+        status::BUFFER_MS.store(in_buffer, Ordering::Relaxed);
+        status::BUFFER_BYTES.store(bytes as u32, Ordering::Relaxed);
 
         window_min = window_min.min(in_buffer);
         if last_status.elapsed().as_secs() >= 10 {
-            log::info!("buffer window: cur {in_buffer} chunks, min {window_min}");
+            log::info!("buffer window: cur {in_buffer}ms ({bytes}B), min {window_min}ms");
+            status::BUFFER_WINDOW_MIN_MS.store(window_min, Ordering::Relaxed);
             window_min = u16::MAX;
             last_status = Instant::now();
         }
-        let mut valid = true;
-        let mut skip_samples = 0;
 
         let low_water = unsafe { esp_get_minimum_free_heap_size() };
         let free = unsafe { esp_get_free_heap_size() };
@@ -98,85 +177,94 @@ fn handle_samples<P: Player>(
             if free_heap - free > 512 {
                 // only log somewhat large changes
                 let block = unsafe { heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT) };
-                log::info!("heap low water mark: free: {free} - min: {low_water} - smallest block: {block}, in-buffer {in_buffer}");
+                log::info!("heap low water mark: free: {free} - min: {low_water} - smallest block: {block}, in-buffer {in_buffer}ms");
             }
             free_heap = free;
         }
 
-        let mut remaining = client_audible_ts - time_base_c.elapsed().into();
-
-        if remaining.sec == -1 && remaining.usec > 0 {
-            remaining.sec = 0;
-            remaining.usec -= 1_000_000;
+        // the per-client latency (Snapweb) plays this client earlier, to make up
+        // for a slow DAC/amp
+        let latency = latency_ms.load(Ordering::Relaxed);
+        // samples reach the DAC only after the player's output buffer drains
+        let output_latency = player
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |p| p.latency_ms().unwrap_or(0));
+        let decision = sched.schedule(
+            client_audible_ts,
+            latency + i32::from(output_latency),
+            &clock,
+        );
+        if !matches!(decision, Decision::Locked { .. }) {
+            // the chunk does not follow on from the last one played
+            resampler.reset();
         }
-
-        if remaining.sec < 0 {
-            // more than 1s late; skipping can't save this chunk
-            log::info!("rem {remaining:?} too late! hard cutting, in-buffer {in_buffer}");
-            valid = false;
-        } else if remaining.sec > 8 {
-            // sanity guard: no sane server buffer is this large, the timestamp is bogus
-            log::info!("rem {remaining:?} too far away! hard cutting, in-buffer {in_buffer}");
-            valid = false;
-        } else if remaining.sec > 0 || remaining.usec > 0 {
-            skip_samples = 0;
-            let tosleep = Duration::from_secs(remaining.sec.unsigned_abs() as u64)
-                + Duration::from_micros(remaining.usec.unsigned_abs() as u64);
-            // can't substract with overflow
-            std::thread::sleep(tosleep - std::cmp::min(tosleep, Duration::from_micros(1500)));
-        } else {
-            let ms_to_skip = (remaining.usec / 1000).unsigned_abs() as u16;
-            skip_samples = ms_to_skip * samples_per_ms;
-            if ms_to_skip > 0 {
-                log::info!(
-                    "skipping {skip_samples} samples = {ms_to_skip}ms, in-buffer {in_buffer}"
-                );
+        if !decision.plays() {
+            log::info!("dropped chunk, in-buffer {in_buffer}ms");
+            // its head still fades out the stream it cuts
+            if !sched.fades_out() {
+                continue;
             }
         }
 
-        if !valid {
+        // I2S keeps the rate of the first stream
+        let Some(rate) = player.lock().unwrap().as_ref().map(|p| p.sample_rate()) else {
             continue;
-        }
-
+        };
+        let rate = u32::from(rate);
         // Guard against chunks coming before the decoder is initialized
-        let Some(ref mut dec) = *dec.lock().unwrap() else {
+        let Some(decoded) = dec.lock().unwrap().as_mut().map(|dec| {
+            // the tail is headroom for frames inserted by the drift correction
+            dec.decode_sample(encoded, &mut dec_sample_buf[..codec::DEC_SAMPLES])
+        }) else {
             continue;
         };
-        let Some(ref mut p) = *player.lock().unwrap() else {
-            continue;
+        let decoded_sample_c = match decoded {
+            Ok(samples) => samples,
+            Err(e) => {
+                log::warn!("dropped chunk that did not decode: {e:?}");
+                resampler.reset();
+                continue;
+            }
         };
-        if samples_per_ms == 1 {
-            samples_per_ms = p.sample_rate() / 1000;
-        }
-
-        match samples {
-            Sample::Data(encoded) => {
-                let decoded_sample_c = dec.decode_sample(&encoded, dec_sample_buf).unwrap();
-                if skip_samples as usize > decoded_sample_c {
-                    log::info!("Tried to skip way too much, skipping the whole sample, in-buffer {in_buffer}");
-                    continue;
-                }
-                let sample = &mut dec_sample_buf[0..decoded_sample_c];
-                let decoded = &mut sample[(skip_samples as usize)..];
-                p.write(decoded).unwrap();
+        let (buf, len) = if resampler.set_rates(source_rate.load(Ordering::Relaxed), rate) {
+            (&mut *dec_sample_buf, decoded_sample_c)
+        } else {
+            // grown once per rate pair, with the same headroom as the decode buffer
+            let size = resampler.max_output(decoded_sample_c)
+                + drift::MAX_FRAMES_PER_CHUNK * codec::CHANNELS;
+            if resample_buf.len() < size {
+                resample_buf.resize(size, 0);
             }
-            Sample::WhiteNoise => {
-                let mut inc = -1;
-                let mut ampl: i16 = 0;
-                // this is a triangle wave, going from -64 to 64
-                // ~4800 entries -> 50ms
-                // can chop amplitude and duration if too noisy
-                for (i, item) in dec_sample_buf.iter_mut().enumerate() {
-                    if (i % 128) == 0 {
-                        inc = -inc;
-                    }
-                    ampl += inc;
-                    *item = ampl / 8;
+            let len = resampler.process(&dec_sample_buf[..decoded_sample_c], resample_buf);
+            (&mut resample_buf[..], len)
+        };
+        eq.process(&mut buf[..len], rate);
+        match sched
+            .play(decision, buf, len, &mut SharedPlayer(&player))
+            .unwrap()
+        {
+            Outcome::Played {
+                padded_frames,
+                skipped_frames,
+            } => {
+                if padded_frames > 0 {
+                    log::info!(
+                        "synced after {padded_frames} frames of silence, in-buffer {in_buffer}ms"
+                    );
+                }
+                if skipped_frames > 0 {
+                    log::info!("skipped {skipped_frames} frames, in-buffer {in_buffer}ms");
                 }
-                log::info!("White noise");
-                p.write(dec_sample_buf).unwrap();
             }
-        };
+            Outcome::SkippedWhole => {
+                log::info!("Tried to skip way too much, skipping the whole sample, in-buffer {in_buffer}ms");
+                continue;
+            }
+            Outcome::FadedOut => continue,
+        }
+        ota::confirm();
     }
     log::warn!("Ran out of samples");
 }
@@ -231,21 +319,32 @@ fn main() -> ! {
     let free = unsafe { esp_get_free_heap_size() };
     log::info!("[startup] heap low water mark: {free}");
 
+    ota::check_pending();
+
     let mut peripherals = Peripherals::take().unwrap();
 
-    let mac = setup(&mut peripherals.modem).unwrap();
-    let i2s = peripherals.i2s0;
-    let dout = peripherals.pins.gpio19;
+    let nvsp = EspDefaultNvsPartition::take().unwrap();
+    let config = Config::load(&nvsp).unwrap_or_else(|e| {
+        log::error!("Could not read configuration: {e:?}, using defaults");
+        Config::default()
+    });
+    log::info!("{config:?}");
 
-    let bclk = peripherals.pins.gpio18;
-    let ws = peripherals.pins.gpio21;
+    let mac = setup(peripherals.modem, &config, nvsp.clone()).unwrap();
+    let i2s = peripherals.i2s0;
+    let i2c = peripherals.i2c0;
 
-    let res = app_main(mac, i2s, dout, bclk, ws);
+    let res = app_main(mac, &config, nvsp, i2s, i2c);
     log::error!("Main returned with {res:?}; will reboot now");
     unsafe { esp_restart() };
 }
 
-fn setup(modem: &mut Modem) -> anyhow::Result<String> {
+/// Credentials patched into the binary by `replacer.py`, if it was run
+fn factory_credentials() -> Option<(&'static str, &'static str)> {
+    // compare bytewise: a placeholder string literal would get patched too
+    if SSID[..31].iter().all(|b| *b == b'J') {
+        return None;
+    }
     let ssid = std::ffi::CStr::from_bytes_until_nul(&SSID)
         .expect("Invalid build SSID")
         .to_str()
@@ -254,11 +353,12 @@ fn setup(modem: &mut Modem) -> anyhow::Result<String> {
         .expect("Invalid build PASS")
         .to_str()
         .expect("PASS is not UTF-8");
+    Some((ssid, pass))
+}
 
-    log::info!("Connecting to SSID '{ssid}'");
-
-    let nvsp = EspDefaultNvsPartition::take().unwrap();
-    let mac = wifi::configure(ssid, pass, nvsp, modem).expect("Could not configure wifi");
+fn setup(modem: Modem, config: &Config, nvsp: EspDefaultNvsPartition) -> anyhow::Result<String> {
+    let mac = wifi::configure(factory_credentials(), config, nvsp, modem)
+        .expect("Could not configure wifi");
 
     log::info!("Syncing time via SNTP");
     let _sntp = start_and_sync_sntp()?;
@@ -271,42 +371,167 @@ fn setup(modem: &mut Modem) -> anyhow::Result<String> {
     Ok(mac)
 }
 
-fn app_main(mac: String, i2s: I2S0, dout: Gpio19, bclk: Gpio18, ws: Gpio21) -> anyhow::Result<()> {
-    cpu::spawn();
-    let mut player_builder = I2sPlayerBuilder::new(i2s, dout, bclk, ws);
+/// A server from mDNS, picked by `mdns::select`, with its instance name
+fn discover(config: &Config, last: Option<&str>) -> Option<(SocketAddr, String)> {
+    match mdns::browse(Duration::from_secs(3)) {
+        Ok(servers) => {
+            for s in &servers {
+                log::info!(
+                    "mDNS: '{}' at {} ({}), txt {:?}",
+                    s.instance,
+                    s.addr,
+                    s.host,
+                    s.txt
+                );
+            }
+            if let Some(s) = mdns::select(&servers, &config.server_names, last) {
+                log::info!("discovered snapcast server '{}' at {}", s.instance, s.addr);
+                return Some((s.addr, s.instance.clone()));
+            }
+            if servers.is_empty() {
+                log::warn!("no snapcast server found via mDNS");
+            } else {
+                log::warn!("none of {:?} found via mDNS", config.server_names);
+            }
+        }
+        Err(e) => log::warn!("mDNS discovery failed: {e:?}"),
+    }
+    None
+}
+
+/// The configured server, looked up on every attempt: a DNS name may move
+fn resolve(host: &str, port: u16) -> Option<SocketAddr> {
+    match (host, port).to_socket_addrs() {
+        Ok(mut addrs) => {
+            let addr = addrs.next();
+            match addr {
+                Some(a) => log::info!("using configured snapcast server {host}:{port} at {a}"),
+                None => log::warn!("{host} has no address"),
+            }
+            addr
+        }
+        Err(e) => {
+            log::warn!("Could not resolve {host}: {e:?}");
+            None
+        }
+    }
+}
 
-    let name = "esp32";
+enum Source<'a> {
+    Configured(&'a str),
+    Mdns,
+}
+
+/// Connects to the configured server and to mDNS in the order `config.mdns`
+/// asks for. Also returns the instance name of an mDNS server.
+fn connect(
+    mac: &str,
+    config: &Config,
+    last: Option<&str>,
+) -> Result<(ConnectedClient, Option<String>), Failure> {
+    let sources = match (config.mdns, config.server_host.as_deref()) {
+        (_, None) => vec![Source::Mdns],
+        (mdns::Mode::Off, Some(host)) => vec![Source::Configured(host)],
+        (mdns::Mode::Primary, Some(host)) => vec![Source::Mdns, Source::Configured(host)],
+        (mdns::Mode::Fallback, Some(host)) => vec![Source::Configured(host), Source::Mdns],
+    };
+    let mut failure = Failure::Discovery;
+    for source in &sources {
+        let found = match source {
+            Source::Configured(host) => resolve(host, config.server_port).map(|addr| (addr, None)),
+            Source::Mdns => discover(config, last).map(|(addr, instance)| (addr, Some(instance))),
+        };
+        let Some((addr, instance)) = found else {
+            continue;
+        };
+        let client = Client::new(mac.to_string(), config.name.clone());
+        match client.connect(addr) {
+            Ok(client) => return Ok((client, instance)),
+            Err(e) => {
+                log::warn!("Could not connect to SnapCast server at {addr}: {e:?}");
+                failure = Failure::Connect;
+            }
+        }
+    }
+    Err(failure)
+}
+
+/// Counts `failure` and waits before the next attempt; an error once the
+/// failures in a row call for a reboot
+fn back_off(supervisor: &mut Supervisor, failure: Failure) -> anyhow::Result<()> {
+    let counter = match failure {
+        Failure::Discovery => &status::DISCOVERY_FAILURES,
+        Failure::Connect => &status::CONNECT_FAILURES,
+        Failure::Protocol { .. } => &status::PROTOCOL_ERRORS,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+    let delay = supervisor.failed(failure, unsafe { esp_random() });
+    status::CONSECUTIVE_FAILURES.store(supervisor.consecutive(), Ordering::Relaxed);
+    let Some(delay) = delay else {
+        anyhow::bail!("{} connection failures in a row", supervisor.consecutive());
+    };
+    log::info!(
+        "{failure:?}, {} failures towards a reboot, retrying in {delay:?}",
+        supervisor.consecutive()
+    );
+    std::thread::sleep(delay);
+    Ok(())
+}
+
+fn app_main(
+    mac: String,
+    config: &Config,
+    nvs: EspDefaultNvsPartition,
+    i2s: I2S0,
+    i2c: I2C0,
+) -> anyhow::Result<()> {
+    cpu::spawn();
+    let mut player_builder = I2sPlayerBuilder::new(i2s, i2c, config)?;
 
     let dec: Arc<Mutex<Option<Decoder>>> = Arc::new(Mutex::new(None));
 
-    // >= 5760 for OPUS (60ms max frame @48k stereo)
-    // >= 2880 for PCM
-    // >= 4700 for flac
-    let mut dec_samples_buf: Vec<i16> = vec![0; 5760];
+    let mut dec_samples_buf: Vec<i16> =
+        vec![0; codec::DEC_SAMPLES + drift::MAX_FRAMES_PER_CHUNK * codec::CHANNELS];
 
     let player: Arc<Mutex<Option<I2sPlayer>>> = Arc::new(Mutex::new(None));
+    let _http = http::start(player.clone())?;
+
+    // allocated once: the heap layout no longer changes per chunk
+    let mut ring = ChunkRing::new(RING_BYTES, MAX_CHUNK_BYTES);
+    let mut enc_buf: Vec<u8> = vec![0; MAX_CHUNK_BYTES];
+    // only used once a stream's rate differs from the I2S rate
+    let mut resample_buf: Vec<i16> = Vec::new();
+    let mut last_server = config::last_server(&nvs).unwrap_or_else(|e| {
+        log::warn!("Could not read the last server: {e:?}");
+        None
+    });
+    let mut supervisor = Supervisor::new(config.reboot_after_failures);
 
     loop {
-        let buf_sample_cnt = Arc::new(AtomicU16::new(0));
-        // Must hold the full server buffer (bufferMs / chunk_ms chunks): the consumer
-        // sleeps on the head chunk until it is audible, so everything else queues here.
-        // 192 slots = 3.8s of 20ms opus chunks (~300-500B each compressed).
-        // A full queue at FLAC chunk sizes (4-5KiB, up to 9KiB) would OOM; the
-        // try_send drop below is the guard -- do not use large server buffers with FLAC.
-        let (sample_tx, sample_rx) = mpsc::sync_channel::<(TimeVal, Sample)>(192);
-        let client = Client::new(mac.clone(), name.into());
-        let addr = loop {
-            match snapcast_client::mdns::discover("_snapcast._tcp.local", Duration::from_secs(3)) {
-                Ok(Some(a)) => break a,
-                Ok(None) => log::warn!("no snapcast server found via mDNS, retrying"),
-                Err(e) => log::warn!("mDNS discovery failed: {e:?}, retrying"),
+        // connecting without Wi-Fi fails for nothing, and counts towards a reboot
+        if !wifi::is_up() {
+            log::info!("Waiting for Wi-Fi");
+            while !wifi::wait_up(Duration::from_secs(60)) {}
+        }
+        let (client, instance) = match connect(&mac, config, last_server.as_deref()) {
+            Ok(connected) => connected,
+            Err(failure) => {
+                back_off(&mut supervisor, failure)?;
+                continue;
             }
-            std::thread::sleep(Duration::from_secs(2));
         };
-        log::info!("discovered snapcast server at {addr}");
-        let client = client
-            .connect(addr)
-            .context("Could not connect to SnapCast server")?;
+        status::CONNECTS.fetch_add(1, Ordering::Relaxed);
+        supervisor.connected();
+        status::CONSECUTIVE_FAILURES.store(0, Ordering::Relaxed);
+        let connected_at = Instant::now();
+        let (producer, consumer) = ring.split();
+        // preferred the next time mDNS finds more than one server
+        if let Some(instance) = instance.filter(|i| last_server.as_ref() != Some(i)) {
+            if let Err(e) = config::set_last_server(&nvs, &instance) {
+                log::warn!("Could not remember the server: {e:?}");
+            }
+            last_server = Some(instance);
+        }
 
         let player_2 = player.clone();
         let player_3 = player.clone();
@@ -314,9 +539,16 @@ fn app_main(mac: String, i2s: I2S0, dout: Gpio19, bclk: Gpio18, ws: Gpio21) -> a
         let dec2 = dec.clone();
         let dec3 = dec.clone();
         let decref = &mut dec_samples_buf;
-        let buf_sample_2 = buf_sample_cnt.clone();
-
-        std::thread::scope(|s| {
+        let encref = &mut enc_buf;
+        let resref = &mut resample_buf;
+        let latency_ms = Arc::new(AtomicI32::new(0));
+        let latency_ms_2 = latency_ms.clone();
+        let source_rate = Arc::new(AtomicU32::new(0));
+        let source_rate_2 = source_rate.clone();
+        let idle = IdleMonitor::new(!config.dma_zero_fill, config.debug_tone);
+        let eq = Equalizer::new(config.eq.clone(), codec::CHANNELS);
+
+        let r = std::thread::scope(|s| {
             let tb = client.time_base();
             // Name the next-spawned pthread at creation: std's Builder::name does
             // not reach the FreeRTOS task name that the CPU monitor reads, esp-idf's
@@ -332,53 +564,74 @@ fn app_main(mac: String, i2s: I2S0, dout: Gpio19, bclk: Gpio18, ws: Gpio21) -> a
             std::thread::Builder::new()
                 .stack_size(28 * 1024)
                 .spawn_scoped(s, move || {
-                    handle_samples(decref, sample_rx, tb, player_2, dec2, buf_sample_2)
+                    handle_samples(
+                        decref,
+                        resref,
+                        encref,
+                        consumer,
+                        tb,
+                        player_2,
+                        dec2,
+                        latency_ms_2,
+                        source_rate_2,
+                        idle,
+                        eq,
+                    )
                 })
                 .unwrap();
             ThreadSpawnConfiguration::default().set().unwrap();
 
             let r = connection_main(
                 client,
+                config,
                 &mut player_builder,
                 player_3,
-                sample_tx,
+                producer,
                 dec3,
-                buf_sample_cnt,
+                latency_ms,
+                source_rate,
             );
-            log::error!("Connection dropped: {r:?}");
-            // sample_tx is dropped here - sample_rx dies -> thread expires -> scope finishes
+            // producer is dropped here - consumer.pop returns Closed -> thread expires -> scope finishes
+            r
         });
         // reset decoder
-        if dec.lock().unwrap().take().is_some() {
-            release_opus_slot();
+        codec::drop_decoder(&mut dec.lock().unwrap());
+        // nothing plays until the next CodecHeader
+        if let Some(p) = player.lock().unwrap().as_mut() {
+            p.power_down();
+        }
+        match r {
+            Ok(()) => log::info!("Reconnecting as requested"),
+            Err(e) if !wifi::is_up() => log::error!("Connection dropped with Wi-Fi: {e:?}"),
+            Err(e) => {
+                log::error!("Connection dropped: {e:?}");
+                let connected_for = connected_at.elapsed();
+                back_off(&mut supervisor, Failure::Protocol { connected_for })?;
+            }
         }
     }
 }
 
-use esp_idf_hal::gpio::{InputPin, OutputPin};
-use esp_idf_hal::peripheral::Peripheral;
-fn connection_main<
-    OP: OutputPin + InputPin,
-    OQ: OutputPin + InputPin,
-    OR: OutputPin + InputPin,
-    P: Peripheral<P = OP> + 'static,
-    Q: Peripheral<P = OQ> + 'static,
-    R: Peripheral<P = OR> + 'static,
->(
+/// Plays from `client`. Returns an error when the connection breaks, `Ok` when
+/// a reconnect is requested over HTTP
+#[allow(clippy::too_many_arguments)] // and everything it shares with the decoder thread
+fn connection_main(
     mut client: ConnectedClient,
-    pb: &mut I2sPlayerBuilder<OP, OQ, OR, P, Q, R>,
+    config: &Config,
+    pb: &mut I2sPlayerBuilder,
     player: Arc<Mutex<Option<I2sPlayer>>>,
-    sample_tx: SyncSender<(TimeVal, Sample)>,
+    mut producer: Producer,
     decoder: Arc<Mutex<Option<Decoder>>>,
-    sample_count: Arc<AtomicU16>,
+    latency_ms: Arc<AtomicI32>,
+    source_rate: Arc<AtomicU32>,
 ) -> anyhow::Result<()> {
     log::info!("Starting a new connection");
 
     let free = unsafe { esp_get_free_heap_size() };
     log::info!("[setup done] heap low water mark: {free}");
 
-    let mut start_vol = 20;
-    let mut last_sample = Instant::now();
+    let mut start_vol = config.start_volume;
+    let mut start_muted = false;
     let mut expired_count: u32 = 0;
     let mut last_expired_log = Instant::now();
     let mut ticks: u64 = 0;
@@ -395,36 +648,35 @@ fn connection_main<
             );
             last_hb = Instant::now();
         }
-        let time_base_c = client.time_base();
+        if status::take_reconnect_request() {
+            log::info!("reconnect requested over HTTP");
+            return Ok(());
+        }
+        if !wifi::is_up() {
+            // the TCP stream can outlive a short drop: keep it, and let the
+            // buffer play out meanwhile instead of starting over
+            log::warn!("Wi-Fi down, pausing with {}ms buffered", producer.fill_ms());
+            while !wifi::wait_up(Duration::from_secs(60)) {}
+            log::info!("Wi-Fi back, resuming");
+        }
         let in_sync = client.synchronized();
+        status::SYNCHRONIZED.store(in_sync, Ordering::Relaxed);
         let msg = client.tick()?;
         ticks += 1;
         match msg {
             Message::CodecHeader(ch) => {
                 log::info!("Initializing player with: {ch:?}");
+                ota::stream_started();
                 let mut dec_guard = decoder.lock().unwrap();
-                // the opus decoder borrows the static slot; the old one must be
-                // dropped and the slot released before the new one can claim it
-                if dec_guard.take().is_some() {
-                    release_opus_slot();
-                }
-                let new_dec = match &ch.metadata {
-                    CodecMetadata::Opus(cfg) => match Decoder::new_opus(cfg, claim_opus_slot()) {
-                        Ok(d) => d,
-                        Err(e) => {
-                            release_opus_slot();
-                            return Err(e);
-                        }
-                    },
-                    other => anyhow::bail!("unsupported codec: {other:?}"),
-                };
-                _ = dec_guard.insert(new_dec);
+                codec::drop_decoder(&mut dec_guard);
+                _ = dec_guard.insert(codec::new_decoder(&ch)?);
+                *status::CODEC.lock().unwrap() = codec::name(&ch.metadata);
+                source_rate.store(ch.metadata.rate() as u32, Ordering::Relaxed);
                 drop(dec_guard);
 
                 // The I2S peripheral can only be created once (init consumes the
                 // GPIOs), so build the player on the first CodecHeader and reuse it
-                // on every reconnect. The ESP only ever decodes opus 48k stereo, so
-                // the sample rate never changes and no reconfigure is needed.
+                // on every reconnect; other rates are resampled in handle_samples.
                 let mut player_guard = player.lock().unwrap();
                 if player_guard.is_none() {
                     log::info!("initializing I2S player");
@@ -432,8 +684,8 @@ fn connection_main<
                 } else {
                     let rate = player_guard.as_ref().unwrap().sample_rate();
                     if rate != ch.metadata.rate() as u16 {
-                        log::warn!(
-                            "codec rate {} != running I2S rate {rate}; I2S cannot be reconfigured, keeping old rate",
+                        log::info!(
+                            "codec rate {} != running I2S rate {rate}, resampling",
                             ch.metadata.rate()
                         );
                     }
@@ -441,6 +693,7 @@ fn connection_main<
                 }
                 let p = player_guard.as_mut().unwrap();
                 p.set_volume(start_vol)?;
+                p.set_muted(start_muted);
                 log::info!("player: calling play()/tx_enable");
                 p.play()?;
                 log::info!("player: play() returned; streaming");
@@ -452,22 +705,12 @@ fn connection_main<
                 // Never block here: Time-sync messages share this TCP stream, so
                 // backpressure would stall clock sync. On a full queue, drop the chunk.
                 if in_sync {
-                    // the freshest packet is in memory twice - as TCP data
-                    // and cloned into the queue
-                    // FIXME: this to_vec allocates on the hot path
-                    // we should instead write directly to a pre-allocated circular buffer
-                    if let Err(e) =
-                        sample_tx.try_send((audible_at, Sample::Data(wc.payload.to_vec())))
-                    {
+                    if let Err(e) = producer.push(audible_at, &wc.payload) {
                         log::warn!(
-                            "queue is full, dropping sample: {e}. encoded size was {}",
+                            "dropping sample: {e}. encoded size was {}",
                             wc.payload.len()
                         )
-                    } else {
-                        sample_count.fetch_add(1, Ordering::AcqRel);
                     }
-
-                    last_sample = Instant::now();
                 }
             }
 
@@ -475,10 +718,13 @@ fn connection_main<
                 last_kind = "settings";
                 let mut p = player.lock().unwrap();
                 log::info!("Server settings {s:?}");
+                latency_ms.store(s.latency, Ordering::Relaxed);
                 // Delay configuration until player is instantiated
                 start_vol = s.volume;
+                start_muted = s.muted;
                 if let Some(p) = p.as_mut() {
                     p.set_volume(s.volume)?;
+                    p.set_muted(s.muted);
                 }
             }
             Message::Expired(lateness) => {
@@ -487,25 +733,17 @@ fn connection_main<
                 // receive loop slower than the stream and it can never catch up
                 expired_count += 1;
                 if last_expired_log.elapsed().as_secs() >= 1 {
-                    let in_buffer = sample_count.load(Ordering::Relaxed);
-                    log::warn!("{expired_count} expired samples dropped, last was {lateness:?} late, buffer has {in_buffer} elems");
+                    let in_buffer = producer.fill_ms();
+                    log::warn!("{expired_count} expired samples dropped, last was {lateness:?} late, buffer has {in_buffer}ms");
                     expired_count = 0;
                     last_expired_log = Instant::now();
                 }
             }
             Message::Nothing => {
                 last_kind = "nothing";
-                // 5 seconds to more easily debug whether it's too loud/too long
-                if last_sample.elapsed().as_secs() > 5 {
-                    let el: TimeVal = time_base_c.elapsed().into();
-                    let two_ms = TimeVal {
-                        sec: 0,
-                        usec: 2_000,
-                    };
-                    let audible_at = el + two_ms;
-                    sample_tx.send((audible_at, Sample::WhiteNoise)).unwrap();
-                    sample_count.fetch_add(1, Ordering::AcqRel);
-                    last_sample = Instant::now();
+                // the output itself goes quiet in handle_samples
+                if let Some(p) = player.lock().unwrap().as_mut() {
+                    p.check_idle();
                 }
             }
         }
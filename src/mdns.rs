@@ -0,0 +1,326 @@
+// Finds snapservers over mDNS. A network can have more than one (say, a
+// production and a test server), so all answers are collected and one is
+// picked by `select`, instead of taking whichever answers first.
+//
+// Queries go out from an ephemeral port, so responders answer by unicast
+// (RFC 6762 6.7) and no multicast group needs joining.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+pub const SERVICE: &str = "_snapcast._tcp.local";
+
+const MDNS: (Ipv4Addr, u16) = (Ipv4Addr::new(224, 0, 0, 251), 5353);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+
+/// How mDNS and a configured server go together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Only the configured server, e.g. where multicast is blocked
+    Off,
+    /// mDNS, then the configured server when mDNS finds none
+    Primary,
+    /// The configured server, then mDNS when it can't be reached
+    Fallback,
+}
+
+impl Mode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::Primary => "primary",
+            Mode::Fallback => "fallback",
+        }
+    }
+}
+
+impl std::str::FromStr for Mode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Mode> {
+        Ok(match s {
+            "off" => Mode::Off,
+            "primary" => Mode::Primary,
+            "fallback" => Mode::Fallback,
+            _ => anyhow::bail!("mDNS is off, primary or fallback"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Server {
+    /// e.g. `Snapcast` for `Snapcast._snapcast._tcp.local`
+    pub instance: String,
+    /// e.g. `studio.local`
+    pub host: String,
+    pub addr: SocketAddr,
+    pub txt: Vec<(String, String)>,
+}
+
+impl Server {
+    /// `name` is an instance name or a host name, with or without `.local`
+    pub fn matches(&self, name: &str) -> bool {
+        let host = |s: &str| {
+            let s = s.trim_end_matches('.');
+            s.strip_suffix(".local").unwrap_or(s).to_ascii_lowercase()
+        };
+        self.instance.eq_ignore_ascii_case(name) || host(&self.host) == host(name)
+    }
+}
+
+/// The first of `names` that is advertised. Without names: `last`, the
+/// instance that was connected to before, if it is advertised, else the first
+/// server by instance name.
+pub fn select<'a>(
+    servers: &'a [Server],
+    names: &[String],
+    last: Option<&str>,
+) -> Option<&'a Server> {
+    if !names.is_empty() {
+        return names
+            .iter()
+            .find_map(|name| servers.iter().find(|s| s.matches(name)));
+    }
+    last.and_then(|last| {
+        servers
+            .iter()
+            .find(|s| s.instance.eq_ignore_ascii_case(last))
+    })
+    .or_else(|| servers.iter().min_by(|a, b| a.instance.cmp(&b.instance)))
+}
+
+/// Asks for `SERVICE` and collects the answers for `timeout`
+pub fn browse(timeout: Duration) -> std::io::Result<Vec<Server>> {
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    let deadline = Instant::now() + timeout;
+    let mut records = Records::default();
+    sock.send_to(&query(&[(SERVICE, TYPE_PTR)]), MDNS)?;
+    let mut asked_again = false;
+    let mut buf = [0u8; 1500];
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
+        }
+        // halfway through, ask for whatever the answers left out
+        if !asked_again && deadline - now < timeout / 2 {
+            asked_again = true;
+            let missing = records.missing();
+            if !missing.is_empty() {
+                let questions: Vec<(&str, u16)> =
+                    missing.iter().map(|(n, t)| (n.as_str(), *t)).collect();
+                sock.send_to(&query(&questions), MDNS)?;
+            }
+        }
+        let wait = (deadline - now)
+            .min(timeout / 2)
+            .max(Duration::from_millis(1));
+        sock.set_read_timeout(Some(wait))?;
+        match sock.recv_from(&mut buf) {
+            Ok((len, _)) => {
+                // a malformed packet is skipped, the others still count
+                _ = records.add(&buf[..len]);
+            }
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records.servers())
+}
+
+/// A query with the unicast-response bit set on each question
+fn query(questions: &[(&str, u16)]) -> Vec<u8> {
+    let mut q = vec![0, 0, 0, 0, 0, questions.len() as u8, 0, 0, 0, 0, 0, 0];
+    for (name, qtype) in questions {
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&0x8001u16.to_be_bytes());
+    }
+    q
+}
+
+/// What the answers so far say, keyed by lowercase names
+#[derive(Default)]
+pub struct Records {
+    /// Full instance names, from PTR records for `SERVICE`
+    instances: Vec<String>,
+    /// Instance name -> port, host
+    srv: HashMap<String, (u16, String)>,
+    txt: HashMap<String, Vec<(String, String)>>,
+    a: HashMap<String, Ipv4Addr>,
+}
+
+impl Records {
+    /// Takes the records of interest from a response packet
+    pub fn add(&mut self, packet: &[u8]) -> Option<()> {
+        let mut r = Reader {
+            buf: packet,
+            pos: 4,
+        };
+        let questions = r.u16()?;
+        // answers, authority and additional records are all read alike
+        let records = r.u16()? as usize + r.u16()? as usize + r.u16()? as usize;
+        for _ in 0..questions {
+            r.name()?;
+            r.pos += 4;
+        }
+        for _ in 0..records {
+            let name = r.name()?.to_ascii_lowercase();
+            let rtype = r.u16()?;
+            r.pos += 6; // class, ttl
+            let len = r.u16()? as usize;
+            let end = r.pos + len;
+            if end > packet.len() {
+                return None;
+            }
+            match rtype {
+                TYPE_PTR if name == SERVICE => {
+                    let instance = r.name()?;
+                    if !self
+                        .instances
+                        .iter()
+                        .any(|i| i.eq_ignore_ascii_case(&instance))
+                    {
+                        self.instances.push(instance);
+                    }
+                }
+                TYPE_SRV => {
+                    r.pos += 4; // priority, weight
+                    let port = r.u16()?;
+                    let host = r.name()?.to_ascii_lowercase();
+                    self.srv.insert(name, (port, host));
+                }
+                TYPE_TXT => {
+                    let mut entries = Vec::new();
+                    while r.pos < end {
+                        let n = usize::from(r.u8()?);
+                        let s = packet.get(r.pos..r.pos + n)?;
+                        r.pos += n;
+                        let s = String::from_utf8_lossy(s);
+                        if let Some((k, v)) = s.split_once('=') {
+                            entries.push((k.into(), v.into()));
+                        } else if !s.is_empty() {
+                            entries.push((s.into(), String::new()));
+                        }
+                    }
+                    self.txt.insert(name, entries);
+                }
+                TYPE_A if len == 4 => {
+                    let ip = packet.get(r.pos..end)?;
+                    self.a
+                        .insert(name, Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]));
+                }
+                _ => {}
+            }
+            r.pos = end;
+        }
+        Some(())
+    }
+
+    /// Names whose SRV or A record has not been seen, with the type to ask
+    fn missing(&self) -> Vec<(String, u16)> {
+        let mut missing = Vec::new();
+        for instance in &self.instances {
+            match self.srv.get(&instance.to_ascii_lowercase()) {
+                None => missing.push((instance.clone(), TYPE_SRV)),
+                Some((_, host)) if !self.a.contains_key(host) => {
+                    missing.push((host.clone(), TYPE_A))
+                }
+                Some(_) => {}
+            }
+        }
+        missing
+    }
+
+    /// The instances with an address
+    pub fn servers(&self) -> Vec<Server> {
+        // SERVICE is lower case, like the keys
+        let suffix = format!(".{SERVICE}");
+        self.instances
+            .iter()
+            .filter_map(|full| {
+                let key = full.to_ascii_lowercase();
+                let (port, host) = self.srv.get(&key)?;
+                let ip = self.a.get(host)?;
+                // a PTR may point anywhere, only instances of the service count
+                if !key.ends_with(&suffix) {
+                    return None;
+                }
+                let instance = full.get(..full.len() - suffix.len())?;
+                Some(Server {
+                    instance: instance.into(),
+                    host: host.clone(),
+                    addr: SocketAddr::from((*ip, *port)),
+                    txt: self.txt.get(&key).cloned().unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u8(&mut self) -> Option<u8> {
+        let b = *self.buf.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    /// A dotted name, following compression pointers
+    fn name(&mut self) -> Option<String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut pos = self.pos;
+        let mut jumped = false;
+        // pointers only go backwards in sane packets; this bounds the others
+        for _ in 0..64 {
+            let len = *self.buf.get(pos)?;
+            match len {
+                0 => {
+                    if !jumped {
+                        self.pos = pos + 1;
+                    }
+                    return Some(labels.join("."));
+                }
+                0xc0.. => {
+                    let target =
+                        usize::from(u16::from_be_bytes([len, *self.buf.get(pos + 1)?]) & 0x3fff);
+                    if !jumped {
+                        self.pos = pos + 2;
+                        jumped = true;
+                    }
+                    pos = target;
+                }
+                0x40.. => return None,
+                _ => {
+                    let label = self.buf.get(pos + 1..pos + 1 + usize::from(len))?;
+                    labels.push(String::from_utf8_lossy(label).into());
+                    pos += 1 + usize::from(len);
+                }
+            }
+        }
+        None
+    }
+}
@@ -0,0 +1,123 @@
+use esp_idf_svc::http::server::{EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::ota::EspOta;
+use esp_idf_sys::*;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::util;
+
+/// A freshly updated image that has not played audio this long after its first
+/// stream started is rolled back
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// First byte of every ESP app image
+const IMAGE_MAGIC: u8 = 0xE9;
+
+static PENDING_VERIFY: AtomicBool = AtomicBool::new(false);
+static TIMER_ARMED: AtomicBool = AtomicBool::new(false);
+
+/// Called at boot: notes whether this is the first boot of an OTA image, which
+/// `stream_started` then gives a deadline to play audio by.
+/// Rebooting before `confirm` (e.g. `app_main` failing) rolls back, that is
+/// done by the bootloader.
+pub fn check_pending() {
+    let mut state: esp_ota_img_states_t = 0;
+    let res =
+        esp!(unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) });
+    // the factory partition has no OTA state
+    if res.is_err() || state != esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY {
+        return;
+    }
+    log::warn!("Running a new image, it is verified by playing the first stream");
+    PENDING_VERIFY.store(true, Ordering::Release);
+}
+
+/// Called on every codec header: the first one on a new image arms a timer
+/// that rolls back to the previous image unless `confirm` is called in time.
+/// Waiting for a stream keeps an update done while nothing plays, e.g. at
+/// night, from being rolled back for want of audio.
+pub fn stream_started() {
+    if !PENDING_VERIFY.load(Ordering::Acquire) || TIMER_ARMED.swap(true, Ordering::AcqRel) {
+        return;
+    }
+    log::warn!("New image must play audio within {VERIFY_TIMEOUT:?}");
+    std::thread::Builder::new()
+        .name("otaverify".into())
+        .stack_size(3072)
+        .spawn(|| {
+            std::thread::sleep(VERIFY_TIMEOUT);
+            if PENDING_VERIFY.load(Ordering::Acquire) {
+                log::error!("New image did not play audio, rolling back");
+                unsafe { esp_ota_mark_app_invalid_rollback_and_reboot() };
+            }
+        })
+        .unwrap();
+}
+
+/// Marks the running image as good; called once audio reached the DAC
+pub fn confirm() {
+    if PENDING_VERIFY.swap(false, Ordering::AcqRel) {
+        log::info!("New image played audio, marking it valid");
+        unsafe { esp_ota_mark_app_valid_cancel_rollback() };
+    }
+}
+
+/// Writes the request body to the inactive OTA partition; esp_ota_end (in
+/// `complete`) verifies the image's checksum and SHA-256 before it is made the
+/// boot partition
+fn receive(req: &mut Request<&mut EspHttpConnection>) -> anyhow::Result<usize> {
+    let mut buf = [0u8; 512];
+    let mut n = req.read(&mut buf)?;
+    // fail before erasing the partition
+    anyhow::ensure!(n > 0 && buf[0] == IMAGE_MAGIC, "not an ESP app image");
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    let mut total = 0;
+    let copied = loop {
+        if n == 0 {
+            break Ok(());
+        }
+        if let Err(e) = update.write(&buf[..n]) {
+            break Err(anyhow::Error::from(e));
+        }
+        total += n;
+        n = match req.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) => break Err(e.into()),
+        };
+    };
+    match copied {
+        Ok(()) => update.complete()?,
+        Err(e) => {
+            update.abort()?;
+            return Err(e);
+        }
+    }
+    Ok(total)
+}
+
+/// `POST /ota` with the raw app image (`espflash save-image`) as body
+pub fn register(server: &mut EspHttpServer<'static>) -> anyhow::Result<()> {
+    server.fn_handler("/ota", Method::Post, |mut req| -> anyhow::Result<()> {
+        log::info!("Receiving OTA image");
+        match receive(&mut req) {
+            Ok(len) => {
+                log::info!("OTA image of {len}B written, rebooting into it");
+                req.into_ok_response()?
+                    .write_all(b"OK, rebooting into the new image\n")?;
+                util::restart_after(Duration::from_secs(1));
+            }
+            Err(e) => {
+                log::error!("OTA failed: {e:?}");
+                let msg = format!("OTA failed: {e}\n");
+                req.into_status_response(500)?.write_all(msg.as_bytes())?;
+            }
+        }
+        Ok(())
+    })?;
+    Ok(())
+}
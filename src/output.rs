@@ -0,0 +1,121 @@
+// What the I2S peripheral puts on the wire, per device: amps and DACs disagree on
+// framing and slot width, and boards in a stereo pair each play one channel.
+
+/// How frames are delimited by WS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Framing {
+    /// I2S: data starts one BCLK after WS changes
+    Philips,
+    /// Left-justified: data starts on the WS edge
+    Msb,
+    /// DSP/PCM short frame sync
+    Pcm,
+}
+
+/// Which audio goes out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Stereo,
+    /// The left channel, on both slots
+    Left,
+    /// The right channel, on both slots
+    Right,
+    /// (L + R) / 2, on both slots
+    Mix,
+    /// Left on the right slot and right on the left
+    Swapped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+    /// Bits per slot: 16, 24 or 32. Samples stay 16 bit and are MSB-aligned,
+    /// so a wider slot carries them as 24/32 bit audio with zeroed low bits.
+    pub slot_bits: u8,
+    pub framing: Framing,
+    pub channel: Channel,
+}
+
+impl Default for OutputFormat {
+    fn default() -> OutputFormat {
+        OutputFormat {
+            slot_bits: 16,
+            framing: Framing::Philips,
+            channel: Channel::Stereo,
+        }
+    }
+}
+
+impl Framing {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Framing::Philips => "philips",
+            Framing::Msb => "msb",
+            Framing::Pcm => "pcm",
+        }
+    }
+}
+
+impl std::str::FromStr for Framing {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Framing> {
+        Ok(match s {
+            "philips" => Framing::Philips,
+            "msb" => Framing::Msb,
+            "pcm" => Framing::Pcm,
+            _ => anyhow::bail!("framing is philips, msb or pcm"),
+        })
+    }
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Stereo => "stereo",
+            Channel::Left => "left",
+            Channel::Right => "right",
+            Channel::Mix => "mix",
+            Channel::Swapped => "swapped",
+        }
+    }
+
+    /// Converts the frames of interleaved stereo `buf` in place
+    pub fn apply(&self, buf: &mut [i16]) {
+        let map: fn(i16, i16) -> [i16; 2] = match self {
+            Channel::Stereo => return,
+            Channel::Left => |l, _| [l, l],
+            Channel::Right => |_, r| [r, r],
+            Channel::Mix => |l, r| {
+                let m = ((i32::from(l) + i32::from(r)) / 2) as i16;
+                [m, m]
+            },
+            Channel::Swapped => |l, r| [r, l],
+        };
+        for frame in buf.chunks_exact_mut(2) {
+            let [l, r] = map(frame[0], frame[1]);
+            frame[0] = l;
+            frame[1] = r;
+        }
+    }
+}
+
+impl std::str::FromStr for Channel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Channel> {
+        Ok(match s {
+            "stereo" => Channel::Stereo,
+            "left" => Channel::Left,
+            "right" => Channel::Right,
+            "mix" => Channel::Mix,
+            "swapped" => Channel::Swapped,
+            _ => anyhow::bail!("channel is stereo, left, right, mix or swapped"),
+        })
+    }
+}
+
+pub fn parse_slot_bits(value: &str) -> anyhow::Result<u8> {
+    let bits: u8 = value.parse()?;
+    anyhow::ensure!(matches!(bits, 16 | 24 | 32), "slot bits are 16, 24 or 32");
+    Ok(bits)
+}
@@ -1,63 +1,168 @@
 use anyhow::anyhow;
 
 use esp_idf_hal::delay::TickType;
-use esp_idf_hal::gpio;
-use esp_idf_hal::gpio::{InputPin, OutputPin};
+use esp_idf_hal::gpio::AnyIOPin;
+use esp_idf_hal::i2c::{I2cConfig, I2cDriver, I2C0};
 use esp_idf_hal::i2s;
 use esp_idf_hal::i2s::config;
 use esp_idf_hal::i2s::I2S0;
-use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::units::Hertz;
 
 use snapcast_client::playback::Player;
 use snapcast_client::proto::CodecHeader;
 
-use crate::util;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-pub struct I2sPlayerBuilder<
-    OP: OutputPin + InputPin,
-    OQ: OutputPin + InputPin,
-    OR: OutputPin + InputPin,
-    P: Peripheral<P = OP> + 'static,
-    Q: Peripheral<P = OQ> + 'static,
-    R: Peripheral<P = OR> + 'static,
-> {
+use crate::amp::Amp;
+use crate::config::Config;
+use crate::dac::{self, Dac};
+use crate::limiter::Limiter;
+use crate::output::{Channel, Framing, OutputFormat};
+use crate::ramp::{Ramp, UNITY};
+use crate::volume::VolumeCurve;
+use crate::{codec, status, util};
+
+// Written samples sit behind the DMA ring before reaching the DAC:
+// 10 * 511 frames = ~106ms @48k, see `latency_ms`
+const DMA_BUFFER_COUNT: u32 = 10;
+const DMA_FRAMES_PER_BUFFER: u32 = 511;
+// one DMA buffer of 16 bit stereo silence
+const FRAME_BYTES: usize = 4;
+static SILENCE: [u8; DMA_FRAMES_PER_BUFFER as usize * FRAME_BYTES] =
+    [0; DMA_FRAMES_PER_BUFFER as usize * FRAME_BYTES];
+
+pub struct I2sPlayerBuilder {
     i2s: Option<I2S0>,
-    dout: Option<P>,
-    bclk: Option<Q>,
-    ws: Option<R>,
+    dout: Option<AnyIOPin>,
+    bclk: Option<AnyIOPin>,
+    ws: Option<AnyIOPin>,
+    /// Only for DACs that need a master clock
+    mclk: Option<AnyIOPin>,
+    mclk_multiple: Option<u16>,
+    /// Only when a codec chip is configured
+    dac: Option<DacBus>,
+    amp: Option<Amp>,
+    start_volume: u8,
+    volume_ramp_ms: u16,
+    volume_curve: VolumeCurve,
+    limiter_threshold_db: Option<i8>,
+    limiter_release_ms: u16,
+    output: OutputFormat,
+    zero_fill: bool,
+}
+
+struct DacBus {
+    chip: dac::Chip,
+    addr: u8,
+    i2c: I2C0,
+    sda: AnyIOPin,
+    scl: AnyIOPin,
 }
 
-impl<
-        OP: OutputPin + InputPin,
-        OQ: OutputPin + InputPin,
-        OR: OutputPin + InputPin,
-        P: Peripheral<P = OP> + 'static,
-        Q: Peripheral<P = OQ> + 'static,
-        R: Peripheral<P = OR> + 'static,
-    > I2sPlayerBuilder<OP, OQ, OR, P, Q, R>
-{
-    pub fn new(i2s: I2S0, dout: P, bclk: Q, ws: R) -> I2sPlayerBuilder<OP, OQ, OR, P, Q, R> {
-        I2sPlayerBuilder {
+impl I2sPlayerBuilder {
+    /// The pins come from the configuration, so they are only known at runtime
+    pub fn new(i2s: I2S0, i2c: I2C0, config: &Config) -> anyhow::Result<I2sPlayerBuilder> {
+        // SAFETY: config only holds output-capable pins, and nothing else in the
+        // firmware drives GPIOs
+        let pin = |n: u8| unsafe { AnyIOPin::new(i32::from(n)) };
+        // disabled now, before I2S starts up
+        let amp = match config.amp_pin {
+            Some(n) => Some(Amp::new(
+                pin(n),
+                config.amp_active_low,
+                Duration::from_millis(config.amp_lead_ms.into()),
+                match config.amp_idle_s {
+                    0 => None,
+                    s => Some(Duration::from_secs(s.into())),
+                },
+            )?),
+            None => None,
+        };
+        Ok(I2sPlayerBuilder {
             i2s: Some(i2s),
-            dout: Some(dout),
-            bclk: Some(bclk),
-            ws: Some(ws),
-        }
+            dout: Some(pin(config.dout_pin)),
+            bclk: Some(pin(config.bclk_pin)),
+            ws: Some(pin(config.ws_pin)),
+            mclk: config.mclk_pin.map(pin),
+            mclk_multiple: config.mclk_multiple,
+            dac: config.dac.map(|chip| DacBus {
+                chip,
+                addr: config.dac_addr.unwrap_or(chip.default_addr()),
+                i2c,
+                sda: pin(config.i2c_sda_pin),
+                scl: pin(config.i2c_scl_pin),
+            }),
+            amp,
+            start_volume: config.start_volume,
+            volume_ramp_ms: config.volume_ramp_ms,
+            volume_curve: config.volume_curve,
+            limiter_threshold_db: config.limiter_threshold_db,
+            limiter_release_ms: config.limiter_release_ms,
+            output: config.output,
+            zero_fill: config.dma_zero_fill,
+        })
     }
     // Heavily inspired from https://github.com/10buttons/awedio_esp32/blob/main/src/lib.rs#L218
     pub fn init(&mut self, ch: &CodecHeader) -> anyhow::Result<I2sPlayer> {
-        let mclk: Option<gpio::AnyIOPin> = None;
+        let out = self.output;
+        let rate = ch.metadata.rate() as u32;
+
+        // always both slots: the channel mode rewrites the frames, so that it
+        // can change while playing
+        let slot_mode = config::SlotMode::Stereo;
+        let data_bits = config::DataBitWidth::Bits16;
+        let slot_config = match out.framing {
+            Framing::Philips => config::StdSlotConfig::philips_slot_default(data_bits, slot_mode),
+            Framing::Msb => config::StdSlotConfig::msb_slot_default(data_bits, slot_mode),
+            Framing::Pcm => config::StdSlotConfig::pcm_slot_default(data_bits, slot_mode),
+        };
+        let slot_config = match out.slot_bits {
+            24 => slot_config.slot_bit_width(config::SlotBitWidth::Bits24),
+            32 => slot_config.slot_bit_width(config::SlotBitWidth::Bits32),
+            _ => slot_config,
+        };
+
+        // MCLK must be a multiple of BCLK = 2 * slot bits * fs
+        let multiple = match self.mclk_multiple {
+            Some(m) => m,
+            None if out.slot_bits == 24 => 384,
+            None => 256,
+        };
+        if multiple % (2 * u16::from(out.slot_bits)) != 0 {
+            anyhow::bail!(
+                "MCLK of {multiple} * fs is not a multiple of BCLK with {} bit slots",
+                out.slot_bits
+            );
+        }
+        let mclk_multiple = match multiple {
+            128 => config::MclkMultiple::M128,
+            256 => config::MclkMultiple::M256,
+            384 => config::MclkMultiple::M384,
+            _ => anyhow::bail!("unsupported MCLK multiple {multiple}"),
+        };
+        let clk_config =
+            config::StdClkConfig::new(rate, config::ClockSource::default(), mclk_multiple);
+        log::info!(
+            "I2S output: {} framing, {} bit slots, {} channel, MCLK {}fs{}",
+            out.framing.as_str(),
+            out.slot_bits,
+            out.channel.as_str(),
+            multiple,
+            if self.mclk.is_some() {
+                ""
+            } else {
+                " (not routed)"
+            },
+        );
 
         let i2s_config = config::StdConfig::new(
             config::Config::default()
-                .auto_clear(true)
-                .dma_buffer_count(10)
-                .frames_per_buffer(511),
-            config::StdClkConfig::from_sample_rate_hz(ch.metadata.rate() as u32),
-            config::StdSlotConfig::philips_slot_default(
-                config::DataBitWidth::Bits16,
-                config::SlotMode::Stereo,
-            ),
+                .auto_clear(self.zero_fill)
+                .dma_buffer_count(DMA_BUFFER_COUNT)
+                .frames_per_buffer(DMA_FRAMES_PER_BUFFER),
+            clk_config,
+            slot_config,
             config::StdGpioConfig::default(),
         );
 
@@ -65,34 +170,294 @@ impl<
         let bclk = self.bclk.take().ok_or(anyhow!("Initialized twice"))?;
         let dout = self.dout.take().ok_or(anyhow!("Initialized twice"))?;
         let ws = self.ws.take().ok_or(anyhow!("Initialized twice"))?;
+        let mclk = self.mclk.take();
+        // what the codec chip gets to run off
+        let dac_mclk = mclk.is_some().then_some(multiple);
         let mut driver = i2s::I2sDriver::new_std_tx(i2s, &i2s_config, bclk, dout, mclk, ws)?;
 
         // Clear TX buffers
         let data: Vec<u8> = vec![0; 128];
         while driver.preload_data(&data)? > 0 {}
 
+        let dac = self.dac.take().and_then(|bus| {
+            let chip = bus.chip.as_str();
+            match open_dac(bus, &out, rate, dac_mclk) {
+                Ok(dac) => Some(dac),
+                Err(e) => {
+                    log::error!("{chip} setup failed: {e:?}; using soft volume");
+                    None
+                }
+            }
+        });
+
         let mut ret = I2sPlayer {
             d: driver,
+            dac,
+            amp: self.amp.take(),
             is_playing: false,
             volume: 0,
+            gain: Ramp::new(0),
+            ramp_frames: usize::from(self.volume_ramp_ms) * rate as usize / 1000,
+            curve: self.volume_curve,
+            limiter: self.limiter_threshold_db.map(|db| {
+                let release = u32::from(self.limiter_release_ms);
+                Limiter::new(f32::from(db), release, rate, codec::CHANNELS)
+            }),
+            muted: false,
             sample_rate: ch.metadata.rate() as u16,
+            last_write: None,
+            last_queued: None,
+            dac_on: false,
+            tone_amp: false,
+            tone_dac: false,
+            channel: out.channel,
         };
-        ret.set_volume(20)?;
+        ret.set_volume(self.start_volume)?;
+        ret.set_channel(out.channel);
         Ok(ret)
     }
 }
+
+fn open_dac(
+    bus: DacBus,
+    format: &OutputFormat,
+    rate: u32,
+    mclk: Option<u16>,
+) -> anyhow::Result<Box<dyn Dac>> {
+    let i2c_config = I2cConfig::new().baudrate(Hertz(100_000));
+    let i2c = I2cDriver::new(bus.i2c, bus.sda, bus.scl, &i2c_config)?;
+    let mut dac = dac::open(bus.chip, i2c, bus.addr);
+    dac.init(format, rate, mclk)?;
+    log::info!("{} at 0x{:02x} initialized", bus.chip.as_str(), bus.addr);
+    Ok(dac)
+}
+
 pub struct I2sPlayer {
     d: i2s::I2sDriver<'static, i2s::I2sTx>,
+    /// Takes volume, mute and power when present
+    dac: Option<Box<dyn Dac>>,
+    amp: Option<Amp>,
     is_playing: bool,
-    volume: i16,
+    /// Soft volume, Q15; the gain ramps towards it unless muted
+    volume: i32,
+    gain: Ramp,
+    ramp_frames: usize,
+    curve: VolumeCurve,
+    /// Runs after the soft volume, on what is actually played
+    limiter: Option<Limiter>,
+    muted: bool,
     sample_rate: u16,
+    /// Last write of audio, for the amplifier's idle time
+    last_write: Option<Instant>,
+    /// Last write of anything, keepalives too: the DMA ring is full after it
+    last_queued: Option<Instant>,
+    /// The codec's outputs are powered, from `play` until `power_down`
+    dac_on: bool,
+    /// The debug tone switched the amplifier or the codec on, and no audio
+    /// was written since: `check_idle` switches them off once it has played
+    tone_amp: bool,
+    tone_dac: bool,
+    channel: Channel,
 }
 
-// Powers of two _may_ make the division faster
-const VOL_STEP_COUNT: i16 = 128;
-
 impl I2sPlayer {
     const BLOCK_TIME: TickType = TickType::new(100_000_000);
+
+    /// Keeps I2S running and the volume untouched; written samples fade to
+    /// silence. A codec chip mutes too, ramping down where it can.
+    pub fn set_muted(&mut self, muted: bool) {
+        if muted != self.muted {
+            log::info!("muted: {muted}");
+        }
+        if let Some(dac) = self.dac.as_mut() {
+            if let Err(e) = dac.set_muted(muted) {
+                log::warn!("Could not mute the DAC: {e:?}");
+            }
+        }
+        self.muted = muted;
+        status::MUTED.store(muted, Ordering::Relaxed);
+        self.update_gain();
+    }
+
+    /// Takes effect from the next write
+    pub fn set_channel(&mut self, channel: Channel) {
+        if channel != self.channel {
+            log::info!("channel: {}", channel.as_str());
+        }
+        self.channel = channel;
+        *status::CHANNEL.lock().unwrap() = channel.as_str();
+    }
+
+    fn update_gain(&mut self) {
+        let target = if self.muted { 0 } else { self.volume };
+        self.gain.set(target, self.ramp_frames);
+    }
+
+    /// Called periodically: disables the amplifier once nothing was written
+    /// for its idle time, and what the debug tone switched on once the tone
+    /// has played. The next write enables them again.
+    pub fn check_idle(&mut self) {
+        if self.tone_amp || self.tone_dac {
+            if self.queued().is_some() {
+                return;
+            }
+            if std::mem::take(&mut self.tone_amp) {
+                if let Some(amp) = self.amp.as_mut() {
+                    if let Err(e) = amp.set(false) {
+                        log::warn!("Could not disable the amplifier: {e:?}");
+                    }
+                }
+            }
+            if std::mem::take(&mut self.tone_dac) {
+                if let Some(dac) = self.dac.as_mut() {
+                    if let Err(e) = dac.set_powered(false) {
+                        log::warn!("Could not power down the DAC: {e:?}");
+                    }
+                }
+                self.dac_on = false;
+            }
+        }
+        let since_write = self.last_write.map(|t| t.elapsed());
+        let Some(amp) = self.amp.as_mut() else {
+            return;
+        };
+        let idle = match (amp.idle, since_write) {
+            (Some(limit), Some(since)) => since >= limit,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        if amp.is_on() && idle {
+            if let Err(e) = amp.set(false) {
+                log::warn!("Could not disable the amplifier: {e:?}");
+            }
+        }
+    }
+
+    /// Powers down the amplifier and the codec's outputs until the next
+    /// `play`; I2S keeps clocking out silence
+    pub fn power_down(&mut self) {
+        if let Some(amp) = self.amp.as_mut() {
+            if let Err(e) = amp.set(false) {
+                log::warn!("Could not disable the amplifier: {e:?}");
+            }
+        }
+        if let Some(dac) = self.dac.as_mut() {
+            if let Err(e) = dac.set_powered(false) {
+                log::warn!("Could not power down the DAC: {e:?}");
+            }
+        }
+        self.dac_on = false;
+        self.tone_amp = false;
+        self.tone_dac = false;
+    }
+
+    /// Audio queued right after a write: the N-1 full buffers and whatever is
+    /// left of the one being played out, on average half of it
+    fn full_latency(&self) -> Duration {
+        let frames = (2 * DMA_BUFFER_COUNT - 1) * DMA_FRAMES_PER_BUFFER / 2;
+        Duration::from_micros(u64::from(frames) * 1_000_000 / u64::from(self.sample_rate))
+    }
+
+    /// Audio still queued in the DMA ring; `None` once it has drained
+    fn queued(&self) -> Option<Duration> {
+        let since_write = self.last_queued?.elapsed();
+        self.full_latency().checked_sub(since_write)
+    }
+
+    /// After an idle period the DMA ring has drained and a write would reach
+    /// the DAC within one buffer. Queueing silence first makes the ring full
+    /// again, so that `latency_ms` holds for the next write.
+    fn prime(&mut self) {
+        if self.queued().is_some() {
+            return;
+        }
+        self.write_silence(((DMA_BUFFER_COUNT - 1) * DMA_FRAMES_PER_BUFFER) as usize);
+        // what it still holds is from before the idle period
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.reset();
+        }
+    }
+
+    /// Written frames wait behind the limiter's look-ahead
+    fn limiter_delay(&self) -> Duration {
+        self.limiter.as_ref().map_or(Duration::ZERO, |l| {
+            Duration::from_micros(l.lookahead() as u64 * 1_000_000 / u64::from(self.sample_rate))
+        })
+    }
+
+    /// Silence that the next write queues on top of `prime`, so that a
+    /// disabled amplifier is on for its lead time before audio reaches it
+    fn amp_lead(&self) -> Duration {
+        match &self.amp {
+            Some(amp) if !amp.is_on() => {
+                let queued = self.queued().unwrap_or_else(|| self.full_latency());
+                amp.lead.saturating_sub(queued)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// Overwrites all of the DMA ring with silence, for a DMA that repeats
+    /// its buffers once it runs dry. Does not count as audio for the
+    /// amplifier's idle time.
+    pub fn flush(&mut self) {
+        self.write_silence((DMA_BUFFER_COUNT * DMA_FRAMES_PER_BUFFER) as usize);
+        self.last_queued = Some(Instant::now());
+    }
+
+    /// Primes the ring and enables a disabled amplifier, behind silence for
+    /// its lead time. True if it was disabled.
+    fn enable_amp(&mut self) -> anyhow::Result<bool> {
+        let lead = self.amp_lead();
+        self.prime();
+        let Some(amp) = self.amp.as_mut().filter(|a| !a.is_on()) else {
+            return Ok(false);
+        };
+        amp.set(true)?;
+        let frames = lead.as_micros() as usize * usize::from(self.sample_rate) / 1_000_000;
+        self.write_silence(frames);
+        Ok(true)
+    }
+
+    /// Writes the debug tone at its own level, not the volume's, unless
+    /// muted. The amplifier and the codec are switched on for it, but it
+    /// does not keep the amplifier from idling.
+    pub fn write_tone(&mut self, buf: &mut [i16]) {
+        if self.muted {
+            return;
+        }
+        self.channel.apply(buf);
+        if !self.dac_on {
+            if let Some(dac) = self.dac.as_mut() {
+                match dac.set_powered(true) {
+                    Ok(()) => self.tone_dac = true,
+                    Err(e) => log::warn!("Could not power up the DAC: {e:?}"),
+                }
+            }
+            self.dac_on = self.tone_dac;
+        }
+        match self.enable_amp() {
+            Ok(enabled) => self.tone_amp |= enabled,
+            Err(e) => log::warn!("Could not enable the amplifier: {e:?}"),
+        }
+        // SAFETY: it's always safe to align i16 to u8
+        let (_, converted, _) = unsafe { buf.align_to::<u8>() };
+        self.d
+            .write_all(converted, Self::BLOCK_TIME.into())
+            .unwrap();
+        self.last_queued = Some(Instant::now());
+    }
+
+    fn write_silence(&mut self, frames: usize) {
+        let mut left = frames * FRAME_BYTES;
+        while left > 0 {
+            let n = left.min(SILENCE.len());
+            self.d
+                .write_all(&SILENCE[..n], Self::BLOCK_TIME.into())
+                .unwrap();
+            left -= n;
+        }
+    }
 }
 impl Player for I2sPlayer {
     fn play(&mut self) -> anyhow::Result<()> {
@@ -100,15 +465,24 @@ impl Player for I2sPlayer {
             self.d.tx_enable().expect("Failed to tx_enable");
             self.is_playing = true;
         }
+        // after tx_enable: codecs sequence their outputs off the running clocks
+        if let Some(dac) = self.dac.as_mut() {
+            dac.set_powered(true)?;
+            self.dac_on = true;
+        }
         Ok(())
     }
 
     fn write(&mut self, buf: &mut [i16]) -> anyhow::Result<()> {
-        // do not apply soft-volume when playing at 100%
-        if self.volume < VOL_STEP_COUNT {
-            for s in buf.iter_mut() {
-                *s = ((*s as i32 * self.volume as i32) / VOL_STEP_COUNT as i32) as i16;
-            }
+        self.channel.apply(buf);
+        self.gain.apply(buf, codec::CHANNELS);
+
+        self.enable_amp()?;
+        // the stream keeps the outputs on from here
+        self.tone_amp = false;
+        self.tone_dac = false;
+        if let Some(limiter) = self.limiter.as_mut() {
+            limiter.process(buf);
         }
 
         // SAFETY: it's always safe to align i16 to u8
@@ -123,23 +497,36 @@ impl Player for I2sPlayer {
             },
             std::time::Duration::from_millis(1),
         );
+        self.last_write = Some(Instant::now());
+        self.last_queued = self.last_write;
         Ok(())
     }
 
+    /// How long until a frame written now reaches the DAC: the ring drains
+    /// between writes, and is primed again once empty
     fn latency_ms(&self) -> anyhow::Result<u16> {
-        Ok(0)
+        let queued = self.queued().unwrap_or_else(|| self.full_latency())
+            + self.amp_lead()
+            + self.limiter_delay();
+        // rounded: truncating would bias the drift correction by half a ms
+        Ok(((queued.as_micros() + 500) / 1000) as u16)
     }
     fn set_volume(&mut self, val: u8) -> anyhow::Result<()> {
-        // convert the 0-100 input range to n/VOL_STEP_COUNT
-        if val == 0 {
-            self.volume = 0;
-            return Ok(());
-        }
-        let volume_float = f64::from(val);
-        let normalized_volume = (volume_float - 1.0) / 99.0;
-        let scaled = normalized_volume.powf(2.0) * f64::from(VOL_STEP_COUNT);
-        self.volume = scaled.round() as i16;
-        log::info!("vol is now {}/{}", self.volume, VOL_STEP_COUNT);
+        status::VOLUME.store(val, Ordering::Relaxed);
+        if let Some(dac) = self.dac.as_mut() {
+            match dac.set_attenuation(self.curve.attenuation_half_db(val)) {
+                Ok(()) => {
+                    self.volume = UNITY;
+                    self.update_gain();
+                    log::info!("vol is now {val} on the DAC");
+                    return Ok(());
+                }
+                Err(e) => log::warn!("Could not set the DAC volume: {e:?}; using soft volume"),
+            }
+        }
+        self.volume = self.curve.gain(val);
+        self.update_gain();
+        log::info!("vol is now {}/{}", self.volume, UNITY);
         Ok(())
     }
 
@@ -0,0 +1,351 @@
+use esp_idf_svc::http::server::{Configuration as HttpConfiguration, EspHttpServer};
+use esp_idf_svc::http::{Headers, Method};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::nvs::{EspNvs, EspNvsPartition, NvsDefault};
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi,
+};
+use esp_idf_sys::{esp_restart, EspError};
+
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::eq;
+
+const NAMESPACE: &str = "wifi";
+
+/// Largest `/save` body accepted
+const MAX_BODY: usize = 2048;
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn form(cfg: &Config) -> String {
+    let server = cfg.server_host.clone().unwrap_or_default();
+    format!(
+        r#"<!DOCTYPE html>
+<html><head><meta name="viewport" content="width=device-width"><title>esp-snapcast</title></head>
+<body><h3>esp-snapcast setup</h3>
+<form method="post" action="/save">
+<p><input name="ssid" placeholder="SSID" maxlength="32"></p>
+<p><input name="pass" type="password" placeholder="Password" maxlength="64"></p>
+<p>Name <input name="name" value="{name}" maxlength="63"></p>
+<p>Server <input name="server_host" value="{server}" maxlength="63" placeholder="IP or host name">
+: <input name="server_port" value="{port}" size="5">
+mDNS <input name="mdns" value="{mdns}" size="8" placeholder="off/primary/fallback"></p>
+<p>mDNS servers <input name="server_names" value="{server_names}" maxlength="63" placeholder="any"></p>
+<p>Reboot after <input name="reboot_after_failures" value="{reboot_after}" size="3"> connection failures in a row (0 = never)</p>
+<p>DOUT <input name="dout_pin" value="{dout}" size="2">
+BCLK <input name="bclk_pin" value="{bclk}" size="2">
+WS <input name="ws_pin" value="{ws}" size="2">
+MCLK <input name="mclk_pin" value="{mclk}" size="2" placeholder="none">
+x<input name="mclk_multiple" value="{mclk_mult}" size="3" placeholder="auto"></p>
+<p>DAC <input name="dac" value="{dac}" size="8" placeholder="none">
+at <input name="dac_addr" value="{dac_addr}" size="4" placeholder="default">
+SDA <input name="i2c_sda_pin" value="{sda}" size="2">
+SCL <input name="i2c_scl_pin" value="{scl}" size="2"></p>
+<p>Amp enable <input name="amp_pin" value="{amp}" size="2" placeholder="none">
+active <input name="amp_active" value="{amp_active}" size="4" placeholder="high/low">,
+on <input name="amp_lead_ms" value="{amp_lead}" size="4">ms before audio,
+off after <input name="amp_idle_s" value="{amp_idle}" size="4">s idle</p>
+<p>Start volume <input name="start_volume" value="{vol}" size="3">,
+changes over <input name="volume_ramp_ms" value="{ramp}" size="4">ms</p>
+<p>Volume curve <input name="volume_curve" value="{curve}" size="6" placeholder="linear/square/cubic/db">
+over <input name="volume_range_db" value="{range_db}" size="3">dB,
+max <input name="max_volume" value="{max_vol}" size="3"></p>
+<p>EQ <input name="eq" value="{eq}" size="40" placeholder="highpass:80,peak:3000:-4:1.4"></p>
+<p>Limit peaks above <input name="limiter_threshold_db" value="{lim_thresh}" size="3" placeholder="off">dB,
+release <input name="limiter_release_ms" value="{lim_release}" size="4">ms</p>
+<p>I2S <input name="i2s_framing" value="{framing}" size="7" placeholder="philips/msb/pcm">
+<input name="i2s_bits" value="{bits}" size="2"> bit slots,
+<input name="i2s_channel" value="{channel}" size="7" placeholder="stereo/left/right/mix/swapped"></p>
+<p>Zero-fill underruns <input name="i2s_zero_fill" value="{zero_fill}" size="5" placeholder="true/false">
+Debug tone <input name="debug_tone" value="{debug_tone}" size="5" placeholder="true/false"></p>
+<p><button>Save and reboot</button></p>
+</form></body></html>"#,
+        name = html_escape(&cfg.name),
+        server = html_escape(&server),
+        port = cfg.server_port,
+        mdns = cfg.mdns.as_str(),
+        server_names = html_escape(&cfg.server_names.join(",")),
+        reboot_after = cfg.reboot_after_failures,
+        dout = cfg.dout_pin,
+        bclk = cfg.bclk_pin,
+        ws = cfg.ws_pin,
+        mclk = cfg.mclk_pin.map(|p| p.to_string()).unwrap_or_default(),
+        mclk_mult = cfg.mclk_multiple.map(|m| m.to_string()).unwrap_or_default(),
+        dac = cfg.dac.map(|c| c.as_str()).unwrap_or_default(),
+        dac_addr = cfg
+            .dac_addr
+            .map(|a| format!("0x{a:02x}"))
+            .unwrap_or_default(),
+        sda = cfg.i2c_sda_pin,
+        scl = cfg.i2c_scl_pin,
+        amp = cfg.amp_pin.map(|p| p.to_string()).unwrap_or_default(),
+        amp_active = if cfg.amp_active_low { "low" } else { "high" },
+        amp_lead = cfg.amp_lead_ms,
+        amp_idle = cfg.amp_idle_s,
+        vol = cfg.start_volume,
+        ramp = cfg.volume_ramp_ms,
+        curve = cfg.volume_curve.curve.map_or("", |c| c.as_str()),
+        range_db = cfg.volume_curve.range_db,
+        max_vol = cfg.volume_curve.max,
+        eq = html_escape(&eq::format_bands(&cfg.eq)),
+        lim_thresh = cfg
+            .limiter_threshold_db
+            .map(|db| db.to_string())
+            .unwrap_or_default(),
+        lim_release = cfg.limiter_release_ms,
+        framing = cfg.output.framing.as_str(),
+        bits = cfg.output.slot_bits,
+        channel = cfg.output.channel.as_str(),
+        zero_fill = cfg.dma_zero_fill,
+        debug_tone = cfg.debug_tone,
+    )
+}
+
+/// Credentials saved by the provisioning portal, if any
+pub(crate) fn stored_credentials(
+    nvs: &EspNvsPartition<NvsDefault>,
+) -> Result<Option<(String, String)>, EspError> {
+    let nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut ssid = [0u8; 33];
+    let mut pass = [0u8; 65];
+    let ssid = nvs.get_str("ssid", &mut ssid)?;
+    let pass = nvs.get_str("pass", &mut pass)?;
+    Ok(match (ssid, pass) {
+        (Some(s), Some(p)) if !s.is_empty() => Some((s.to_string(), p.to_string())),
+        _ => None,
+    })
+}
+
+fn save_credentials(
+    nvs: &EspNvsPartition<NvsDefault>,
+    ssid: &str,
+    pass: &str,
+) -> anyhow::Result<()> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_str("ssid", ssid)?;
+    nvs.set_str("pass", pass)?;
+    Ok(())
+}
+
+/// Decodes an application/x-www-form-urlencoded value
+fn url_decode(s: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => out.push(b' '),
+            b'%' => {
+                let hi = (bytes.next()? as char).to_digit(16)?;
+                let lo = (bytes.next()? as char).to_digit(16)?;
+                out.push((hi * 16 + lo) as u8);
+            }
+            b => out.push(b),
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+fn form_fields(body: &str) -> impl Iterator<Item = (&str, Option<String>)> {
+    body.split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| (k, url_decode(v)))
+}
+
+fn form_field(body: &str, name: &str) -> Option<String> {
+    form_fields(body)
+        .find(|(k, _)| *k == name)
+        .and_then(|(_, v)| v)
+}
+
+/// Answers every A query with our own address, so that phones pop up their
+/// captive portal page on association
+fn dns_hijack(ip: Ipv4Addr) -> std::io::Result<()> {
+    let sock = UdpSocket::bind("0.0.0.0:53")?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, peer) = sock.recv_from(&mut buf)?;
+        // header + one question; anything else is ignored
+        if len < 12 || buf[4..6] != [0, 1] {
+            continue;
+        }
+        // the question is labels up to the root, then type and class;
+        // whatever follows (an EDNS OPT record) is dropped
+        let mut end = 12;
+        while end < len && buf[end] != 0 {
+            end += 1 + usize::from(buf[end]);
+        }
+        let end = end + 5;
+        if end > len || end + 16 > buf.len() {
+            continue;
+        }
+        buf[2] = 0x81; // response, recursion desired
+        buf[3] = 0x80; // recursion available, no error
+        buf[6..12].copy_from_slice(&[0, 1, 0, 0, 0, 0]); // 1 answer, nothing else
+        let answer = [
+            0xc0, 0x0c, // name: pointer to the question
+            0x00, 0x01, // type A
+            0x00, 0x01, // class IN
+            0x00, 0x00, 0x00, 0x3c, // ttl 60s
+            0x00, 0x04, // rdlength
+        ];
+        buf[end..end + 12].copy_from_slice(&answer);
+        buf[end + 12..end + 16].copy_from_slice(&ip.octets());
+        _ = sock.send_to(&buf[..end + 16], peer);
+    }
+}
+
+/// Returns once the form is saved, or after `timeout` without a request
+fn serve(
+    wifi: &mut BlockingWifi<EspWifi<'_>>,
+    nvs: &EspNvsPartition<NvsDefault>,
+    config: &Config,
+    timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mac = wifi.wifi().ap_netif().get_mac()?;
+    let ssid = format!("esp-snapcast-{:02X}{:02X}", mac[4], mac[5]);
+    log::warn!("Starting provisioning access point '{ssid}'");
+
+    wifi.stop()?;
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: heapless::String::try_from(ssid.as_str()).unwrap(),
+        auth_method: AuthMethod::None,
+        channel: 1,
+        max_connections: 2,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!("Provisioning portal at http://{ip}/");
+
+    std::thread::Builder::new()
+        .name("dns".into())
+        .stack_size(4096)
+        .spawn(move || {
+            if let Err(e) = dns_hijack(ip) {
+                log::error!("captive DNS failed: {e:?}");
+            }
+        })?;
+
+    let saved = Arc::new(AtomicBool::new(false));
+    let saved_2 = saved.clone();
+    // someone filling in the form is not cut off
+    let last_request = Arc::new(Mutex::new(Instant::now()));
+    let last_request_2 = last_request.clone();
+    let last_request_3 = last_request.clone();
+    let nvs = nvs.clone();
+    let page = form(config);
+    let config = config.clone();
+
+    // the listen socket + one connection, see sdkconfig.defaults
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        max_open_sockets: 1,
+        // any URL (captive portal probes) gets the form
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    server.fn_handler(
+        "/save",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            *last_request_2.lock().unwrap() = Instant::now();
+            if req.content_len().is_some_and(|l| l >= MAX_BODY as u64) {
+                req.into_status_response(413)?
+                    .write_all(b"form too large")?;
+                return Ok(());
+            }
+            // on the heap, off the server task's stack
+            let mut body = vec![0u8; MAX_BODY];
+            let mut len = 0;
+            while len < body.len() {
+                match req.read(&mut body[len..])? {
+                    0 => break,
+                    n => len += n,
+                }
+            }
+            // a full buffer may have cut the form short: never save that
+            if len == body.len() {
+                req.into_status_response(413)?
+                    .write_all(b"form too large")?;
+                return Ok(());
+            }
+            let body = std::str::from_utf8(&body[..len])?;
+            let (Some(ssid), Some(pass)) = (form_field(body, "ssid"), form_field(body, "pass"))
+            else {
+                req.into_status_response(400)?
+                    .write_all(b"missing ssid or pass")?;
+                return Ok(());
+            };
+            if ssid.is_empty() || ssid.len() > 32 || pass.len() > 64 {
+                req.into_status_response(400)?
+                    .write_all(b"invalid ssid or pass")?;
+                return Ok(());
+            }
+            let mut config = config.clone();
+            for (key, value) in form_fields(body) {
+                if key == "ssid" || key == "pass" {
+                    continue;
+                }
+                let value = value.unwrap_or_default();
+                if let Err(e) = config.set(key, &value) {
+                    let msg = format!("invalid {key}: {e}");
+                    req.into_status_response(400)?.write_all(msg.as_bytes())?;
+                    return Ok(());
+                }
+            }
+            config.save(&nvs)?;
+            save_credentials(&nvs, &ssid, &pass)?;
+            log::info!("Saved credentials for SSID '{ssid}'");
+            req.into_ok_response()?
+                .write_all(b"Saved, rebooting into station mode")?;
+            saved_2.store(true, Ordering::Release);
+            Ok(())
+        },
+    )?;
+    server.fn_handler("/*", Method::Get, move |req| -> anyhow::Result<()> {
+        *last_request_3.lock().unwrap() = Instant::now();
+        req.into_ok_response()?.write_all(page.as_bytes())?;
+        Ok(())
+    })?;
+
+    while !saved.load(Ordering::Acquire) {
+        if let Some(timeout) = timeout {
+            if last_request.lock().unwrap().elapsed() >= timeout {
+                log::warn!("Nothing submitted in {timeout:?}, retrying station mode");
+                return Ok(());
+            }
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    // let the response go out before the radio goes down
+    std::thread::sleep(Duration::from_secs(1));
+    Ok(())
+}
+
+/// Hosts a SoftAP with a form to enter Wi-Fi credentials and the device
+/// configuration, stores them in NVS and reboots into station mode. With a
+/// `timeout`, also reboots when nothing is submitted for that long: the AP
+/// that could not be reached may be back by then.
+pub(crate) fn run(
+    mut wifi: BlockingWifi<EspWifi<'_>>,
+    nvs: EspNvsPartition<NvsDefault>,
+    config: &Config,
+    timeout: Option<Duration>,
+) -> ! {
+    if let Err(e) = serve(&mut wifi, &nvs, config, timeout) {
+        log::error!("Provisioning failed: {e:?}");
+        std::thread::sleep(Duration::from_secs(5));
+    }
+    unsafe { esp_restart() };
+}
@@ -0,0 +1,75 @@
+// Gain changes spread over many frames: a step in the gain of a playing
+// signal is a click, and a slider moved in steps is a zipper.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+/// Gains are Q15: this is 1.0
+pub const UNITY: i32 = 1 << 15;
+
+/// A gain that moves linearly to its target, by the same step every frame
+#[derive(Debug, Clone)]
+pub struct Ramp {
+    gain: i32,
+    target: i32,
+    step: i32,
+}
+
+impl Ramp {
+    pub fn new(gain: i32) -> Ramp {
+        Ramp {
+            gain,
+            target: gain,
+            step: 0,
+        }
+    }
+
+    pub fn gain(&self) -> i32 {
+        self.gain
+    }
+
+    /// Moves from the current gain to `target` over `frames`, or at once for 0
+    pub fn set(&mut self, target: i32, frames: usize) {
+        self.target = target;
+        let distance = (target - self.gain).abs();
+        if frames == 0 || distance == 0 {
+            self.gain = target;
+            self.step = 0;
+            return;
+        }
+        let frames = frames.min(i32::MAX as usize) as i32;
+        // rounded up: the ramp is over within `frames`
+        self.step = (distance + frames - 1) / frames;
+    }
+
+    /// Starts from `from` instead of the current gain
+    pub fn restart(&mut self, from: i32, target: i32, frames: usize) {
+        self.gain = from;
+        self.set(target, frames);
+    }
+
+    /// Scales `buf`, which holds frames of `channels` interleaved samples
+    pub fn apply(&mut self, buf: &mut [i16], channels: usize) {
+        if self.gain == self.target {
+            match self.gain {
+                UNITY => {}
+                0 => buf.fill(0),
+                gain => buf.iter_mut().for_each(|s| *s = scale(*s, gain)),
+            }
+            return;
+        }
+        for frame in buf.chunks_mut(channels) {
+            self.gain = if self.gain < self.target {
+                (self.gain + self.step).min(self.target)
+            } else {
+                (self.gain - self.step).max(self.target)
+            };
+            for s in frame {
+                *s = scale(*s, self.gain);
+            }
+        }
+    }
+}
+
+fn scale(sample: i16, gain: i32) -> i16 {
+    ((i32::from(sample) * gain) >> 15) as i16
+}
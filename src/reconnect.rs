@@ -0,0 +1,108 @@
+// When to try again after losing the snapserver. Retrying at a fixed rate
+// hammers a server that is restarting, and every speaker in the house would
+// come back in lockstep; rebooting on the first failure throws away a working
+// Wi-Fi association for what is usually a server-side problem.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+use std::time::Duration;
+
+/// A connection that lasted this long was healthy: the retries after it
+/// start from the shortest wait again
+pub const STABLE: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with jitter
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub const fn new(base: Duration, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Doubles from `base` up to `max` with every call, then takes a random
+    /// point in the upper half of that, from `random`
+    pub fn next(&mut self, random: u32) -> Duration {
+        let cap = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = cap / 2;
+        let jitter = (half.as_millis() as u64 * u64::from(random)) >> 32;
+        half + Duration::from_millis(jitter)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Neither mDNS nor the configured host gave an address. Only backs off:
+    /// with no server up, a reboot finds none either
+    Discovery,
+    /// A server was found, but did not accept the connection
+    Connect,
+    /// The connection broke after `connected_for`: a read failed, or the
+    /// server sent something that could not be played
+    Protocol { connected_for: Duration },
+}
+
+/// Counts connection failures since the last connection and spaces out the
+/// retries
+pub struct Supervisor {
+    backoff: Backoff,
+    /// 0 never reboots
+    max_failures: u16,
+    consecutive: u16,
+}
+
+impl Supervisor {
+    pub fn new(max_failures: u16) -> Supervisor {
+        Supervisor {
+            backoff: Backoff::new(Duration::from_secs(1), Duration::from_secs(60)),
+            max_failures,
+            consecutive: 0,
+        }
+    }
+
+    /// A server accepted the connection
+    pub fn connected(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// The wait before the next attempt, or `None` when `max_failures` in a
+    /// row call for a reboot
+    pub fn failed(&mut self, failure: Failure, random: u32) -> Option<Duration> {
+        match failure {
+            Failure::Discovery => return Some(self.backoff.next(random)),
+            Failure::Connect => {}
+            Failure::Protocol { connected_for } => {
+                if connected_for >= STABLE {
+                    // a dropped connection after a good while: back soon
+                    self.backoff.reset();
+                }
+            }
+        }
+        self.consecutive = self.consecutive.saturating_add(1);
+        if self.max_failures != 0 && self.consecutive >= self.max_failures {
+            return None;
+        }
+        Some(self.backoff.next(random))
+    }
+
+    /// Connect and protocol failures since the last connection
+    pub fn consecutive(&self) -> u16 {
+        self.consecutive
+    }
+}
@@ -0,0 +1,112 @@
+// Converts the decoded audio to the rate of the I2S clock. The driver is set
+// up once, for the first stream, and a reconnect may bring another rate: a
+// 44.1kHz stream on a 48kHz clock would play ~9% fast and sharp.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+/// Frames kept from the previous chunk: the interpolation reads one frame
+/// before the output position and two after it
+const HISTORY: usize = 3;
+
+/// Cubic (Catmull-Rom) interpolation between input frames, in fixed point
+pub struct Resampler {
+    channels: usize,
+    from: u32,
+    to: u32,
+    /// Input frames per output frame, Q32
+    step: u64,
+    /// Position of the next output frame in history + input, in frames, Q32
+    pos: u64,
+    history: Vec<i16>,
+}
+
+impl Resampler {
+    pub fn new(channels: usize) -> Resampler {
+        Resampler {
+            channels,
+            from: 0,
+            to: 0,
+            step: 1 << 32,
+            pos: 1 << 32,
+            history: vec![0; HISTORY * channels],
+        }
+    }
+
+    /// True if there is nothing to convert between `from` and `to`
+    pub fn set_rates(&mut self, from: u32, to: u32) -> bool {
+        if (from, to) != (self.from, self.to) {
+            if from != to {
+                log::info!("resampling {from}Hz to {to}Hz");
+            }
+            self.from = from;
+            self.to = to;
+            self.step = (u64::from(from) << 32) / u64::from(to);
+            self.reset();
+        }
+        from == to
+    }
+
+    /// Forgets the previous chunk, for audio that does not continue it
+    pub fn reset(&mut self) {
+        self.history.fill(0);
+        self.pos = 1 << 32;
+    }
+
+    /// The most samples `process` writes for `samples` of input
+    pub fn max_output(&self, samples: usize) -> usize {
+        let frames = (samples / self.channels) as u64;
+        (frames * u64::from(self.to) / u64::from(self.from) + 2) as usize * self.channels
+    }
+
+    /// Converts the frames of interleaved `input` into `out`, which holds at
+    /// least `max_output` samples. Returns the samples written; the output
+    /// lags the input by two frames.
+    pub fn process(&mut self, input: &[i16], out: &mut [i16]) -> usize {
+        let ch = self.channels;
+        let input_frames = input.len() / ch;
+        let frames = HISTORY + input_frames;
+        let history = &self.history;
+        let sample = |frame: usize, c: usize| {
+            i64::from(if frame < HISTORY {
+                history[frame * ch + c]
+            } else {
+                input[(frame - HISTORY) * ch + c]
+            })
+        };
+
+        let mut written = 0;
+        while (self.pos >> 32) as usize + 2 < frames {
+            let i = (self.pos >> 32) as usize;
+            // Q15
+            let t = ((self.pos >> 17) & 0x7fff) as i64;
+            for c in 0..ch {
+                let (xm1, x0, x1, x2) = (
+                    sample(i - 1, c),
+                    sample(i, c),
+                    sample(i + 1, c),
+                    sample(i + 2, c),
+                );
+                let a = 3 * (x0 - x1) + x2 - xm1;
+                let b = 2 * xm1 - 5 * x0 + 4 * x1 - x2;
+                let d = x1 - xm1;
+                // x0 + t/2 * (d + t * (b + t * a))
+                let y = x0 + ((t * (d + ((t * (b + ((t * a) >> 15))) >> 15))) >> 16);
+                out[written + c] = y.clamp(i64::from(i16::MIN), i64::from(i16::MAX)) as i16;
+            }
+            written += ch;
+            self.pos += self.step;
+        }
+
+        // the last frames are the history of the next chunk
+        if input_frames >= HISTORY {
+            let tail = &input[(input_frames - HISTORY) * ch..input_frames * ch];
+            self.history.copy_from_slice(tail);
+        } else {
+            let keep = (HISTORY - input_frames) * ch;
+            self.history.copy_within(input_frames * ch.., 0);
+            self.history[keep..].copy_from_slice(&input[..input_frames * ch]);
+        }
+        self.pos -= (input_frames as u64) << 32;
+        written
+    }
+}
@@ -0,0 +1,260 @@
+// The queue of encoded chunks between the connection and the playback thread.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+use snapcast_client::proto::TimeVal;
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Every entry is prefixed by the chunk's audible timestamp and payload length:
+/// sec (i32 LE), usec (i32 LE), len (u32 LE)
+pub const HEADER_LEN: usize = 12;
+
+pub enum Popped<'a> {
+    Chunk(TimeVal, &'a [u8]),
+    /// Nothing arrived in time
+    Timeout,
+    /// The producer is gone
+    Closed,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    /// Not enough free bytes right now; the consumer is behind
+    Full,
+    /// The payload can never fit the consumer's scratch buffer
+    TooLarge,
+}
+
+impl std::fmt::Display for PushError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PushError::Full => write!(f, "ring buffer is full"),
+            PushError::TooLarge => write!(f, "chunk is larger than the max chunk size"),
+        }
+    }
+}
+
+/// Fixed-capacity queue of encoded chunks, budgeted in bytes rather than slots.
+///
+/// The storage is allocated once at boot and reused for every connection, so a
+/// full queue can't OOM regardless of the codec's chunk size, and the hot path
+/// does not touch the heap.
+/// Entries may wrap around the end of the storage; they are copied in/out in up
+/// to two parts.
+pub struct ChunkRing {
+    buf: Box<[UnsafeCell<u8>]>,
+    max_chunk: usize,
+    /// Byte positions in `[0, cap)`: `head` is only written by the consumer,
+    /// `tail` by the producer. Monotonic positions would not do: `usize` is 32
+    /// bits, and a capacity that does not divide 2^32 breaks `pos % cap` on
+    /// the wrap, hours into a connection.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    /// Bytes between `head` and `tail`, which can't tell full from empty
+    fill: AtomicUsize,
+    closed: AtomicBool,
+    /// Audible timestamps (ms, wrapping) of the newest pushed and the last
+    /// popped chunk; their difference is the amount of audio queued
+    newest_ms: AtomicU32,
+    popped_ms: AtomicU32,
+    lock: Mutex<()>,
+    cv: Condvar,
+}
+
+// SAFETY: the producer only writes the free region and the consumer only reads
+// the filled region; `fill` publishes the hand-over with Release/Acquire
+unsafe impl Sync for ChunkRing {}
+
+fn ts_ms(ts: &TimeVal) -> u32 {
+    (ts.sec as i64 * 1000 + ts.usec as i64 / 1000) as u32
+}
+
+impl ChunkRing {
+    pub fn new(capacity: usize, max_chunk: usize) -> ChunkRing {
+        assert!(capacity >= HEADER_LEN + max_chunk);
+        ChunkRing {
+            buf: (0..capacity).map(|_| UnsafeCell::new(0)).collect(),
+            max_chunk,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            fill: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            newest_ms: AtomicU32::new(0),
+            popped_ms: AtomicU32::new(0),
+            lock: Mutex::new(()),
+            cv: Condvar::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Empties the ring and hands out the only producer/consumer pair.
+    /// Taking `&mut self` is what makes this single-producer/single-consumer:
+    /// no other handle can exist while the pair is alive.
+    pub fn split(&mut self) -> (Producer<'_>, Consumer<'_>) {
+        *self.head.get_mut() = 0;
+        *self.tail.get_mut() = 0;
+        *self.fill.get_mut() = 0;
+        *self.closed.get_mut() = false;
+        *self.newest_ms.get_mut() = 0;
+        *self.popped_ms.get_mut() = 0;
+        let ring = &*self;
+        (Producer { ring }, Consumer { ring })
+    }
+
+    pub fn fill_bytes(&self) -> usize {
+        self.fill.load(Ordering::Acquire)
+    }
+
+    /// Milliseconds of audio between the last chunk handed to the consumer and
+    /// the newest chunk queued
+    pub fn fill_ms(&self) -> u16 {
+        if self.fill_bytes() == 0 {
+            return 0;
+        }
+        let newest = self.newest_ms.load(Ordering::Relaxed);
+        let popped = self.popped_ms.load(Ordering::Relaxed);
+        (newest.wrapping_sub(popped) as i32).clamp(0, u16::MAX as i32) as u16
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        UnsafeCell::raw_get(self.buf.as_ptr())
+    }
+
+    /// `pos` may be past the end by less than `cap`, it wraps.
+    /// SAFETY: caller must own `[pos, pos + data.len())`
+    unsafe fn copy_in(&self, pos: usize, data: &[u8]) {
+        let cap = self.buf.len();
+        let start = pos % cap;
+        let first = data.len().min(cap - start);
+        std::ptr::copy_nonoverlapping(data.as_ptr(), self.ptr().add(start), first);
+        std::ptr::copy_nonoverlapping(data.as_ptr().add(first), self.ptr(), data.len() - first);
+    }
+
+    /// `pos` may be past the end by less than `cap`, it wraps.
+    /// SAFETY: caller must own `[pos, pos + out.len())`
+    unsafe fn copy_out(&self, pos: usize, out: &mut [u8]) {
+        let cap = self.buf.len();
+        let start = pos % cap;
+        let first = out.len().min(cap - start);
+        std::ptr::copy_nonoverlapping(self.ptr().add(start), out.as_mut_ptr(), first);
+        std::ptr::copy_nonoverlapping(self.ptr(), out.as_mut_ptr().add(first), out.len() - first);
+    }
+
+    fn notify(&self) {
+        // taking the lock orders this against the consumer's check-then-wait
+        let _guard = self.lock.lock().unwrap();
+        self.cv.notify_one();
+    }
+}
+
+pub struct Producer<'a> {
+    ring: &'a ChunkRing,
+}
+
+impl Producer<'_> {
+    pub fn fill_ms(&self) -> u16 {
+        self.ring.fill_ms()
+    }
+
+    /// Never blocks: on a full ring the chunk is rejected and the caller decides
+    /// what to drop
+    pub fn push(&mut self, audible_at: TimeVal, payload: &[u8]) -> Result<(), PushError> {
+        let ring = self.ring;
+        if payload.len() > ring.max_chunk {
+            return Err(PushError::TooLarge);
+        }
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let free = ring.capacity() - ring.fill.load(Ordering::Acquire);
+        if HEADER_LEN + payload.len() > free {
+            return Err(PushError::Full);
+        }
+
+        let mut header = [0u8; HEADER_LEN];
+        header[0..4].copy_from_slice(&audible_at.sec.to_le_bytes());
+        header[4..8].copy_from_slice(&audible_at.usec.to_le_bytes());
+        header[8..12].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+        // SAFETY: [tail, tail + free) is only ever touched by the producer
+        unsafe {
+            ring.copy_in(tail, &header);
+            ring.copy_in(tail + HEADER_LEN, payload);
+        }
+        ring.newest_ms.store(ts_ms(&audible_at), Ordering::Relaxed);
+        let len = HEADER_LEN + payload.len();
+        ring.tail
+            .store((tail + len) % ring.capacity(), Ordering::Relaxed);
+        ring.fill.fetch_add(len, Ordering::Release);
+        ring.notify();
+        Ok(())
+    }
+}
+
+impl Drop for Producer<'_> {
+    /// Mirrors dropping an mpsc sender: the consumer's `pop` returns `None`
+    fn drop(&mut self) {
+        self.ring.closed.store(true, Ordering::Release);
+        self.ring.notify();
+    }
+}
+
+pub struct Consumer<'a> {
+    ring: &'a ChunkRing,
+}
+
+impl Consumer<'_> {
+    pub fn fill_bytes(&self) -> usize {
+        self.ring.fill_bytes()
+    }
+
+    pub fn fill_ms(&self) -> u16 {
+        self.ring.fill_ms()
+    }
+
+    /// Blocks for up to `timeout` until a chunk is available and copies it into
+    /// `out`, which must be at least `max_chunk` bytes long.
+    /// Returns `Closed` once the producer is gone; queued chunks are discarded,
+    /// as their connection (and time base) is dead.
+    pub fn pop<'b>(&mut self, out: &'b mut [u8], timeout: Duration) -> Popped<'b> {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let deadline = Instant::now() + timeout;
+        let mut guard = ring.lock.lock().unwrap();
+        loop {
+            if ring.closed.load(Ordering::Acquire) {
+                return Popped::Closed;
+            }
+            if ring.fill.load(Ordering::Acquire) != 0 {
+                break;
+            }
+            let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+                return Popped::Timeout;
+            };
+            guard = ring.cv.wait_timeout(guard, left).unwrap().0;
+        }
+        drop(guard);
+
+        let mut header = [0u8; HEADER_LEN];
+        // SAFETY: [head, tail) is only ever touched by the consumer
+        unsafe { ring.copy_out(head, &mut header) };
+        let sec = i32::from_le_bytes(header[0..4].try_into().unwrap());
+        let usec = i32::from_le_bytes(header[4..8].try_into().unwrap());
+        let len = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+        let out = &mut out[0..len];
+        unsafe { ring.copy_out(head + HEADER_LEN, out) };
+
+        let audible_at = TimeVal { sec, usec };
+        ring.popped_ms.store(ts_ms(&audible_at), Ordering::Relaxed);
+        ring.head.store(
+            (head + HEADER_LEN + len) % ring.capacity(),
+            Ordering::Relaxed,
+        );
+        ring.fill.fetch_sub(HEADER_LEN + len, Ordering::Release);
+        Popped::Chunk(audible_at, out)
+    }
+}
@@ -0,0 +1,269 @@
+// Playback scheduling: decides, for every chunk taken off the ring buffer,
+// whether it is played right away, after some silence, trimmed at the front or
+// dropped, and feeds back-to-back chunks to the drift correction.
+//
+// The player is a FIFO: a frame written now reaches the DAC after whatever is
+// still queued, which the player reports as its latency. Sleeping would only
+// drain that queue, so an early chunk is preceded by silence instead.
+//
+// Audio fades in after every hard sync. When a playing stream is cut, the
+// head of the next chunk still continues what was playing: it is faded out in
+// place of the frames that are skipped, or ahead of the silence.
+//
+// Time only comes in through `Clock` and audio only goes out through `Player`,
+// and nothing here calls into ESP-IDF: sim/ builds this file for the host and
+// replays synthetic timelines against it.
+
+use snapcast_client::playback::Player;
+use snapcast_client::proto::TimeVal;
+
+use std::time::{Duration, Instant};
+
+use crate::drift::{self, DriftCorrector};
+use crate::ramp::{Ramp, UNITY};
+
+/// No sane server buffer is this large
+const MAX_AHEAD_SEC: i32 = 8;
+
+/// Interleaved samples of silence written per call while padding
+const SILENCE_SAMPLES: usize = 1024;
+
+/// Audio starts and stops over this long instead of at once
+const FADE: Duration = Duration::from_millis(5);
+
+pub trait Clock {
+    /// Time since the connection's time base
+    fn elapsed(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    time_base: Instant,
+}
+
+impl SystemClock {
+    pub fn new(time_base: Instant) -> SystemClock {
+        SystemClock { time_base }
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> Duration {
+        self.time_base.elapsed()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// More than 1s late; skipping can't save this chunk
+    TooLate,
+    /// Further out than any sane server buffer; the timestamp is bogus
+    TooFar,
+    /// (Re)synchronize: a positive offset is silence to write before the
+    /// chunk, a negative one is dropped from its front
+    HardSync { offset_us: i64 },
+    /// Playing back to back; the scheduling error goes to the drift correction
+    Locked { error_us: i64 },
+}
+
+impl Decision {
+    pub fn plays(&self) -> bool {
+        matches!(self, Decision::HardSync { .. } | Decision::Locked { .. })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Played {
+        padded_frames: usize,
+        skipped_frames: usize,
+    },
+    /// Nothing was left of the chunk after skipping
+    SkippedWhole,
+    /// The chunk is not played, only its head to fade out the stream
+    FadedOut,
+}
+
+fn ms_to_timeval(ms: i32) -> TimeVal {
+    TimeVal {
+        sec: ms / 1000,
+        usec: (ms % 1000) * 1000,
+    }
+}
+
+pub struct Scheduler {
+    drift: DriftCorrector,
+    /// Interleaved channels of the decoded audio
+    channels: usize,
+    fade: Duration,
+    /// Fades in what plays after a hard sync
+    fade_in: Ramp,
+    /// The last decision dropped the chunk that continued a playing stream
+    cut: bool,
+}
+
+impl Scheduler {
+    pub fn new(channels: usize) -> Scheduler {
+        Scheduler {
+            drift: DriftCorrector::new(),
+            channels,
+            fade: FADE,
+            fade_in: Ramp::new(UNITY),
+            cut: false,
+        }
+    }
+
+    /// Fades over `fade` instead; zero plays every chunk as it is
+    pub fn with_fade(mut self, fade: Duration) -> Scheduler {
+        self.fade = fade;
+        self
+    }
+
+    /// Decides how to play the chunk audible at `audible_at` (on the server's
+    /// timeline). `offset_ms` is how much earlier than that it must be written:
+    /// the per-client latency plus the player's output latency.
+    pub fn schedule(&mut self, audible_at: TimeVal, offset_ms: i32, clock: &dyn Clock) -> Decision {
+        let mut remaining = audible_at - ms_to_timeval(offset_ms) - clock.elapsed().into();
+
+        if remaining.sec == -1 && remaining.usec > 0 {
+            remaining.sec = 0;
+            remaining.usec -= 1_000_000;
+        }
+
+        let error_us = i64::from(remaining.sec) * 1_000_000 + i64::from(remaining.usec);
+
+        let decision = if remaining.sec < 0 {
+            log::info!("rem {remaining:?} too late! hard cutting");
+            Decision::TooLate
+        } else if remaining.sec > MAX_AHEAD_SEC {
+            log::info!("rem {remaining:?} too far away! hard cutting");
+            Decision::TooFar
+        } else if self.drift.is_locked() && error_us.abs() < drift::HARD_SYNC_US {
+            Decision::Locked { error_us }
+        } else {
+            Decision::HardSync {
+                offset_us: error_us,
+            }
+        };
+        self.cut = !decision.plays() && self.drift.is_locked();
+        if !decision.plays() {
+            self.drift.reset();
+        }
+        decision
+    }
+
+    /// The chunk the last decision dropped should still be decoded and
+    /// passed to `play`, to fade out the stream it cut
+    pub fn fades_out(&self) -> bool {
+        self.cut && !self.fade.is_zero()
+    }
+
+    /// Writes the decoded chunk `buf[..len]` to `player` as `decision` says.
+    /// `buf` needs `drift::MAX_FRAMES_PER_CHUNK` frames of headroom past `len`.
+    pub fn play(
+        &mut self,
+        decision: Decision,
+        buf: &mut [i16],
+        len: usize,
+        player: &mut dyn Player,
+    ) -> anyhow::Result<Outcome> {
+        let rate = u32::from(player.sample_rate());
+        let to_frames = |us: i64| (us.unsigned_abs() * u64::from(rate) / 1_000_000) as usize;
+        let fade_frames = to_frames(self.fade.as_micros() as i64);
+        let ch = self.channels;
+        let mut len = len;
+        // a hard sync while locked is a jump in a playing stream
+        let cut = matches!(decision, Decision::HardSync { .. })
+            && self.drift.is_locked()
+            && fade_frames > 0;
+        let (pad_frames, skip_frames) = match decision {
+            Decision::TooLate | Decision::TooFar if self.cut => {
+                self.cut = false;
+                let head = &mut buf[..fade_frames.min(len / ch) * ch];
+                self.fade_out(head);
+                player.write(head)?;
+                return Ok(Outcome::FadedOut);
+            }
+            Decision::TooLate | Decision::TooFar => anyhow::bail!("{decision:?} is not played"),
+            Decision::HardSync { offset_us } if offset_us > 0 => (to_frames(offset_us), 0),
+            Decision::HardSync { offset_us } => (0, to_frames(offset_us)),
+            Decision::Locked { error_us } => {
+                len = self.drift.correct(error_us, rate, buf, len, self.channels);
+                (0, 0)
+            }
+        };
+        let skip_samples = skip_frames * ch;
+        if skip_samples > 0 && skip_samples >= len {
+            if cut {
+                let head = &mut buf[..fade_frames.min(len / ch) * ch];
+                self.fade_out(head);
+                player.write(head)?;
+            }
+            self.drift.reset();
+            return Ok(Outcome::SkippedWhole);
+        }
+
+        let mut fade_in_from = skip_samples;
+        if cut && pad_frames > 0 {
+            // the chunk starts over after the silence
+            let frames = fade_frames
+                .min(pad_frames)
+                .min(len / ch)
+                .min(SILENCE_SAMPLES / ch);
+            let mut head = [0i16; SILENCE_SAMPLES];
+            let head = &mut head[..frames * ch];
+            head.copy_from_slice(&buf[..frames * ch]);
+            self.fade_out(head);
+            player.write(head)?;
+            self.write_silence(pad_frames - frames, player)?;
+        } else {
+            self.write_silence(pad_frames, player)?;
+        }
+        if cut && skip_frames > 0 {
+            // played instead of the last skipped frames, so nothing moves
+            let frames = fade_frames.min(skip_frames).min((len - skip_samples) / ch);
+            fade_in_from = skip_samples + frames * ch;
+            buf.copy_within(..frames * ch, skip_samples);
+            self.fade_out(&mut buf[skip_samples..fade_in_from]);
+        }
+
+        if let Decision::HardSync { .. } = decision {
+            // the following chunks play back to back
+            self.drift.lock();
+            self.fade_in.restart(0, UNITY, fade_frames);
+        }
+        self.fade_in.apply(&mut buf[fade_in_from..len], ch);
+        player.write(&mut buf[skip_samples..len])?;
+        Ok(Outcome::Played {
+            padded_frames: pad_frames,
+            skipped_frames: skip_frames,
+        })
+    }
+
+    /// Blocks for as long as the silence takes to queue, like a sleep would,
+    /// but without letting the queued audio drain
+    fn write_silence(&self, frames: usize, player: &mut dyn Player) -> anyhow::Result<()> {
+        let mut silence = [0i16; SILENCE_SAMPLES];
+        let mut left = frames * self.channels;
+        while left > 0 {
+            let n = left.min(SILENCE_SAMPLES - SILENCE_SAMPLES % self.channels);
+            // the player may have scaled the previous batch in place
+            silence[..n].fill(0);
+            player.write(&mut silence[..n])?;
+            left -= n;
+        }
+        Ok(())
+    }
+
+    /// Fades `buf` out over its whole length
+    fn fade_out(&self, buf: &mut [i16]) {
+        let mut ramp = Ramp::new(UNITY);
+        ramp.set(0, buf.len() / self.channels);
+        ramp.apply(buf, self.channels);
+    }
+
+    /// Whatever was written was not part of the stream (idle keepalive): the
+    /// next chunk is not contiguous with the last one
+    pub fn interrupt(&mut self) {
+        self.drift.reset();
+    }
+}
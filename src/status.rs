@@ -0,0 +1,102 @@
+use esp_idf_svc::sys::*;
+
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU8, Ordering};
+use std::sync::Mutex;
+
+use crate::wifi;
+
+// Published by the threads that own the data, read by the HTTP server
+
+pub static BUFFER_MS: AtomicU16 = AtomicU16::new(0);
+pub static BUFFER_BYTES: AtomicU32 = AtomicU32::new(0);
+/// Minimum of the last complete 10s window
+pub static BUFFER_WINDOW_MIN_MS: AtomicU16 = AtomicU16::new(0);
+/// Free CPU budget per core in %, `u8::MAX` until the first report
+pub static CPU_FREE: [AtomicU8; 2] = [AtomicU8::new(u8::MAX), AtomicU8::new(u8::MAX)];
+pub static VOLUME: AtomicU8 = AtomicU8::new(0);
+pub static MUTED: AtomicBool = AtomicBool::new(false);
+pub static SYNCHRONIZED: AtomicBool = AtomicBool::new(false);
+pub static CODEC: Mutex<&str> = Mutex::new("none");
+/// Channel mode, see `output::Channel`
+pub static CHANNEL: Mutex<&str> = Mutex::new("stereo");
+/// State of the output, see `idle::State`
+pub static OUTPUT: Mutex<&str> = Mutex::new("idle");
+
+/// Connections to a snapserver, and failures to get or keep one, see
+/// `reconnect::Failure`
+pub static CONNECTS: AtomicU32 = AtomicU32::new(0);
+pub static DISCOVERY_FAILURES: AtomicU32 = AtomicU32::new(0);
+pub static CONNECT_FAILURES: AtomicU32 = AtomicU32::new(0);
+pub static PROTOCOL_ERRORS: AtomicU32 = AtomicU32::new(0);
+/// Since the last connection, not counting discovery; the ESP reboots at the
+/// configured limit
+pub static CONSECUTIVE_FAILURES: AtomicU16 = AtomicU16::new(0);
+
+/// Set by the HTTP server, consumed by `connection_main`
+static RECONNECT: AtomicBool = AtomicBool::new(false);
+
+pub fn request_reconnect() {
+    RECONNECT.store(true, Ordering::Release);
+}
+
+pub fn take_reconnect_request() -> bool {
+    RECONNECT.swap(false, Ordering::AcqRel)
+}
+
+pub fn rssi() -> Option<i8> {
+    // SAFETY: plain C struct, all-zeroes is a valid value
+    let mut info: wifi_ap_record_t = unsafe { std::mem::zeroed() };
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut info) }).ok()?;
+    Some(info.rssi)
+}
+
+fn json_opt<T: std::fmt::Display>(v: Option<T>) -> String {
+    v.map_or("null".into(), |v| v.to_string())
+}
+
+pub fn to_json() -> String {
+    let cpu = |core: usize| {
+        let free = CPU_FREE[core].load(Ordering::Relaxed);
+        json_opt((free != u8::MAX).then_some(free))
+    };
+    let (free, min_free, largest_block) = unsafe {
+        (
+            esp_get_free_heap_size(),
+            esp_get_minimum_free_heap_size(),
+            heap_caps_get_largest_free_block(MALLOC_CAP_DEFAULT),
+        )
+    };
+    format!(
+        concat!(
+            "{{",
+            r#""buffer":{{"ms":{},"bytes":{},"window_min_ms":{}}},"#,
+            r#""heap":{{"free":{},"min_free":{},"largest_block":{}}},"#,
+            r#""cpu_free":{{"core0":{},"core1":{}}},"#,
+            r#""wifi":{{"rssi":{},"link":{}}},"#,
+            r#""server":{{"connects":{},"discovery_failures":{},"connect_failures":{},"protocol_errors":{},"consecutive_failures":{}}},"#,
+            r#""codec":"{}","output":"{}","channel":"{}","volume":{},"muted":{},"synchronized":{}"#,
+            "}}\n"
+        ),
+        BUFFER_MS.load(Ordering::Relaxed),
+        BUFFER_BYTES.load(Ordering::Relaxed),
+        BUFFER_WINDOW_MIN_MS.load(Ordering::Relaxed),
+        free,
+        min_free,
+        largest_block,
+        cpu(0),
+        cpu(1),
+        json_opt(rssi()),
+        wifi::LINK.lock().unwrap().to_json(),
+        CONNECTS.load(Ordering::Relaxed),
+        DISCOVERY_FAILURES.load(Ordering::Relaxed),
+        CONNECT_FAILURES.load(Ordering::Relaxed),
+        PROTOCOL_ERRORS.load(Ordering::Relaxed),
+        CONSECUTIVE_FAILURES.load(Ordering::Relaxed),
+        CODEC.lock().unwrap(),
+        OUTPUT.lock().unwrap(),
+        CHANNEL.lock().unwrap(),
+        VOLUME.load(Ordering::Relaxed),
+        MUTED.load(Ordering::Relaxed),
+        SYNCHRONIZED.load(Ordering::Relaxed),
+    )
+}
@@ -11,3 +11,12 @@ pub(crate) fn measure_exec<F: FnOnce()>(name: &str, f: F, threshold: Duration) {
         }
     }
 }
+
+/// Reboots from a background thread, so that e.g. an HTTP response can still
+/// go out
+pub(crate) fn restart_after(delay: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(delay);
+        unsafe { esp_idf_svc::sys::esp_restart() };
+    });
+}
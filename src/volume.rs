@@ -0,0 +1,106 @@
+// Maps the server's 0-100 volume onto a gain. Loudness is heard on a log
+// scale, so a linear map crams all the audible change into the bottom of the
+// slider; which curve suits depends on the amp and the speakers.
+//
+// Nothing here calls into ESP-IDF; sim/ builds this file for the host.
+
+use crate::ramp::UNITY;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Curve {
+    Linear,
+    Square,
+    Cubic,
+    /// Equal steps in dB over `VolumeCurve::range_db`
+    Db,
+}
+
+impl Curve {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Curve::Linear => "linear",
+            Curve::Square => "square",
+            Curve::Cubic => "cubic",
+            Curve::Db => "db",
+        }
+    }
+}
+
+impl std::str::FromStr for Curve {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Curve> {
+        Ok(match s {
+            "linear" => Curve::Linear,
+            "square" => Curve::Square,
+            "cubic" => Curve::Cubic,
+            "db" => Curve::Db,
+            _ => anyhow::bail!("volume curve is linear, square, cubic or db"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeCurve {
+    /// `None` keeps each output's own: `Curve::Db` on a codec chip,
+    /// `Curve::Square` on the samples
+    pub curve: Option<Curve>,
+    /// Volume 1 is this far below volume 100, for `Curve::Db`
+    pub range_db: u8,
+    /// Volume 100 plays as this volume
+    pub max: u8,
+}
+
+impl Default for VolumeCurve {
+    fn default() -> VolumeCurve {
+        VolumeCurve {
+            curve: None,
+            range_db: 60,
+            max: 100,
+        }
+    }
+}
+
+impl VolumeCurve {
+    /// 0.0-1.0 for a 1-100 volume: 1 is the bottom of the curve, 100 is
+    /// `max`
+    fn position(&self, vol: u8) -> f64 {
+        let vol = vol.clamp(1, 100);
+        f64::from(vol - 1) / 99.0 * f64::from(self.max.clamp(1, 100)) / 100.0
+    }
+
+    /// 0.0-1.0 for a 0-100 volume
+    fn amplitude(&self, curve: Curve, vol: u8) -> f64 {
+        if vol == 0 {
+            return 0.0;
+        }
+        let x = self.position(vol);
+        match curve {
+            Curve::Linear => x,
+            Curve::Square => x * x,
+            Curve::Cubic => x * x * x,
+            Curve::Db => 10f64.powf(-f64::from(self.range_db) * (1.0 - x) / 20.0),
+        }
+    }
+
+    /// Q15 gain for the samples
+    pub fn gain(&self, vol: u8) -> i32 {
+        let curve = self.curve.unwrap_or(Curve::Square);
+        (self.amplitude(curve, vol) * f64::from(UNITY)).round() as i32
+    }
+
+    /// For codec chips: attenuation in 0.5dB steps, `None` for silence
+    pub fn attenuation_half_db(&self, vol: u8) -> Option<u32> {
+        match self.curve.unwrap_or(Curve::Db) {
+            // exact steps, not through the amplitude
+            Curve::Db if vol > 0 => {
+                let att = f64::from(self.range_db) * 2.0 * (1.0 - self.position(vol));
+                Some((att + 1e-9) as u32)
+            }
+            curve => {
+                let amplitude = self.amplitude(curve, vol);
+                (amplitude > 0.0).then(|| (-40.0 * amplitude.log10()).round() as u32)
+            }
+        }
+    }
+}
@@ -1,35 +1,94 @@
 use esp_idf_hal::modem::Modem;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 use esp_idf_sys::esp;
-use esp_idf_sys::{esp_wifi_set_ps, wifi_ps_type_t_WIFI_PS_NONE};
+use esp_idf_sys::{esp_random, esp_wifi_set_ps, wifi_ps_type_t_WIFI_PS_NONE};
 
 use esp_idf_svc::nvs::{EspNvsPartition, NvsDefault};
 use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
 use esp_idf_svc::wifi::{ClientConfiguration, Configuration};
 use esp_idf_sys::EspError;
 
-/// The nvs stores the RF calibration data, which allows for faster connection
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::link::Link;
+use crate::provision;
+use crate::reconnect::Backoff;
+use crate::status;
+
+/// Failed association attempts before falling back to the provisioning portal
+const CONNECT_ATTEMPTS: u8 = 5;
+
+/// With credentials stored, the portal gives station mode another go after
+/// this long unused, e.g. once a rebooting router is back
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How often the supervisor records the RSSI while the link is up
+pub const RSSI_PERIOD: Duration = Duration::from_secs(10);
+
+/// Written by the event handler and the supervisor; `LINK_CHANGED` is
+/// notified whenever the link goes up or down
+pub(crate) static LINK: Mutex<Link> = Mutex::new(Link::new());
+static LINK_CHANGED: Condvar = Condvar::new();
+
+pub(crate) fn is_up() -> bool {
+    LINK.lock().unwrap().is_up()
+}
+
+/// Waits up to `timeout` for the link to be up; true if it is
+pub(crate) fn wait_up(timeout: Duration) -> bool {
+    let link = LINK.lock().unwrap();
+    let (link, _) = LINK_CHANGED
+        .wait_timeout_while(link, timeout, |l| !l.is_up())
+        .unwrap();
+    link.is_up()
+}
+
+/// The nvs stores the RF calibration data, which allows for faster connection,
+/// and the credentials saved by the provisioning portal.
+/// Credentials in NVS take precedence over the `factory` ones patched into the
+/// binary; without either, or when association keeps failing, this does not
+/// return: it hosts the provisioning portal and reboots.
+/// Once associated, a supervisor thread takes over the connection.
 pub(crate) fn configure(
-    ssid: &str,
-    pass: &str,
+    factory: Option<(&str, &str)>,
+    config: &Config,
     nvs: EspNvsPartition<NvsDefault>,
-    modem: &mut Modem,
+    modem: Modem,
 ) -> Result<[u8; 6], EspError> {
     // Configure Wifi
     let sysloop = EspSystemEventLoop::take()?;
 
     // logs association drops (e.g. reason 16 = group key handshake timeout during
-    // the AP's hourly GTK rekey) with reason code and RSSI
+    // the AP's hourly GTK rekey) with reason code and RSSI, and wakes up the
+    // supervisor
     let sub = sysloop.subscribe::<esp_idf_svc::wifi::WifiEvent, _>(|event| match event {
         esp_idf_svc::wifi::WifiEvent::StaDisconnected(d) => {
-            log::warn!("wifi disconnected: {d:?}")
+            log::warn!("wifi disconnected: {d:?}");
+            LINK.lock().unwrap().disconnected(d.reason());
+            LINK_CHANGED.notify_all();
         }
         esp_idf_svc::wifi::WifiEvent::StaConnected(c) => log::info!("wifi connected: {c:?}"),
         _ => (),
     })?;
     std::mem::forget(sub);
 
-    let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sysloop.clone(), Some(nvs))?, sysloop)?;
+    let stored = provision::stored_credentials(&nvs)?;
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::new(modem, sysloop.clone(), Some(nvs.clone()))?,
+        sysloop,
+    )?;
+
+    let (ssid, pass) = match (&stored, factory) {
+        (Some((ssid, pass)), _) => (ssid.as_str(), pass.as_str()),
+        (None, Some(creds)) => creds,
+        (None, None) => {
+            log::warn!("No Wi-Fi credentials stored");
+            provision::run(wifi, nvs, config, None);
+        }
+    };
+    log::info!("Connecting to SSID '{ssid}'");
 
     wifi.set_configuration(&Configuration::Client(ClientConfiguration {
         ssid: heapless::String::try_from(ssid).unwrap(),
@@ -40,7 +99,14 @@ pub(crate) fn configure(
     wifi.start()?;
     // disable radio power saving; makes connectivity generally faster
     esp!(unsafe { esp_wifi_set_ps(wifi_ps_type_t_WIFI_PS_NONE) })?;
-    wifi.connect()?;
+    let mut attempt = 1;
+    while let Err(e) = wifi.connect() {
+        log::warn!("Wi-Fi association attempt {attempt}/{CONNECT_ATTEMPTS} failed: {e:?}");
+        if attempt >= CONNECT_ATTEMPTS {
+            provision::run(wifi, nvs, config, Some(PORTAL_TIMEOUT));
+        }
+        attempt += 1;
+    }
 
     // Wait until the network interface is up
     wifi.wait_netif_up()?;
@@ -48,6 +114,50 @@ pub(crate) fn configure(
     let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
     let mac = wifi.wifi().ap_netif().get_mac()?;
     log::info!("IP config: {:?}", ip_info);
-    std::mem::forget(wifi);
+    set_up();
+
+    std::thread::Builder::new()
+        .name("wifisup".into())
+        .stack_size(4096)
+        .spawn(move || supervise(wifi))
+        .unwrap();
     Ok(mac)
 }
+
+fn set_up() {
+    LINK.lock().unwrap().connected();
+    LINK_CHANGED.notify_all();
+}
+
+/// Reassociates after a drop, which the driver does not do by itself, and
+/// samples the RSSI while the link is up
+fn supervise(mut wifi: BlockingWifi<EspWifi<'static>>) -> ! {
+    let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(30));
+    loop {
+        let link = LINK.lock().unwrap();
+        let (mut link, timeout) = LINK_CHANGED
+            .wait_timeout_while(link, RSSI_PERIOD, |l| l.is_up())
+            .unwrap();
+        if timeout.timed_out() {
+            if let Some(rssi) = status::rssi() {
+                link.sample_rssi(rssi);
+            }
+            continue;
+        }
+        drop(link);
+
+        log::info!("Reconnecting to Wi-Fi");
+        match wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+            Ok(()) => {
+                log::info!("Wi-Fi is back");
+                backoff.reset();
+                set_up();
+            }
+            Err(e) => {
+                let delay = backoff.next(unsafe { esp_random() });
+                log::warn!("Wi-Fi reconnect failed: {e:?}, retrying in {delay:?}");
+                std::thread::sleep(delay);
+            }
+        }
+    }
+}